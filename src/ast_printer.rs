@@ -0,0 +1,242 @@
+use crate::parser::{Expr, Statement, Term};
+
+const INDENT: &str = "  ";
+
+/// Formats `program` as a tree, one node per line with its children indented below it,
+/// e.g. to see how operators are grouped.
+pub fn format_program(program: &[Statement]) -> String {
+    let mut out = String::new();
+    for statement in program {
+        format_statement(&mut out, 0, statement);
+    }
+    out
+}
+
+fn push_line(out: &mut String, depth: usize, text: &str) {
+    out.push_str(&INDENT.repeat(depth));
+    out.push_str(text);
+    out.push('\n');
+}
+
+fn format_statement(out: &mut String, depth: usize, statement: &Statement) {
+    let child = depth + 1;
+    match statement {
+        Statement::If(condition, block, otherwise) => {
+            push_line(out, depth, "if");
+            format_expr(out, child, condition);
+            format_statement(out, child, block);
+            if let Some(otherwise) = otherwise {
+                push_line(out, depth, "else");
+                format_statement(out, child, otherwise);
+            }
+        }
+        Statement::While(condition, index, block) => {
+            push_line(out, depth, &with_index("while", index));
+            format_expr(out, child, condition);
+            format_statement(out, child, block);
+        }
+        Statement::DoWhile(block, condition) => {
+            push_line(out, depth, "do");
+            format_statement(out, child, block);
+            push_line(out, depth, "while");
+            format_expr(out, child, condition);
+        }
+        Statement::Repeat(count, index, block) => {
+            push_line(out, depth, &with_index("repeat", index));
+            format_expr(out, child, count);
+            format_statement(out, child, block);
+        }
+        Statement::For(variable, start, end, block) => {
+            push_line(out, depth, &format!("for {variable}"));
+            format_expr(out, child, start);
+            format_expr(out, child, end);
+            format_statement(out, child, block);
+        }
+        Statement::ForEntries(key, value, map, block) => {
+            push_line(out, depth, &format!("for ({key}, {value})"));
+            format_expr(out, child, map);
+            format_statement(out, child, block);
+        }
+        Statement::Block(statements) => {
+            push_line(out, depth, "block");
+            for statement in statements {
+                format_statement(out, child, statement);
+            }
+        }
+        Statement::Assignment(variable, expr, declaration) => {
+            let keyword = if *declaration { "let " } else { "" };
+            push_line(out, depth, &format!("{keyword}{variable} :="));
+            format_expr(out, child, expr);
+        }
+        Statement::IndexedAssignment(variable, index, expr) => {
+            push_line(out, depth, &format!("{variable}[] :="));
+            format_expr(out, child, index);
+            format_expr(out, child, expr);
+        }
+        Statement::Print(exprs) => format_exprs(out, depth, "print", exprs),
+        Statement::EPrint(exprs) => format_exprs(out, depth, "eprint", exprs),
+        Statement::Write(exprs) => format_exprs(out, depth, "write", exprs),
+        Statement::Swap(a, b) => push_line(out, depth, &format!("swap {a} {b}")),
+        Statement::Debug => push_line(out, depth, "debug"),
+        Statement::Assert(expr) => {
+            push_line(out, depth, "assert");
+            format_expr(out, child, expr);
+        }
+        Statement::Break => push_line(out, depth, "break"),
+        Statement::Continue => push_line(out, depth, "continue"),
+        Statement::FunctionDef(name, parameters, body) => {
+            push_line(out, depth, &format!("fn {name}({})", parameters.join(", ")));
+            format_statement(out, child, body);
+        }
+        Statement::Return(expr) => {
+            push_line(out, depth, "return");
+            format_expr(out, child, expr);
+        }
+        Statement::Expression(expr) => format_expr(out, depth, expr),
+        Statement::Empty => push_line(out, depth, ";"),
+        // The lines only matter for runtime errors.
+        Statement::AtLine(_, statement) => format_statement(out, depth, statement),
+    }
+}
+
+fn with_index(keyword: &str, index: &Option<String>) -> String {
+    match index {
+        Some(index) => format!("{keyword} as {index}"),
+        None => keyword.to_string(),
+    }
+}
+
+fn format_exprs(out: &mut String, depth: usize, label: &str, exprs: &[Expr]) {
+    push_line(out, depth, label);
+    for expr in exprs {
+        format_expr(out, depth + 1, expr);
+    }
+}
+
+fn format_expr(out: &mut String, depth: usize, expr: &Expr) {
+    let (operator, operands) = match expr {
+        Expr::Add(l, r) => ("+", [l, r]),
+        Expr::Subtract(l, r) => ("-", [l, r]),
+        Expr::Multiply(l, r) => ("*", [l, r]),
+        Expr::Divide(l, r) => ("/", [l, r]),
+        Expr::Modulo(l, r) => ("%", [l, r]),
+        Expr::LogicalOr(l, r) => ("||", [l, r]),
+        Expr::LogicalAnd(l, r) => ("&&", [l, r]),
+        Expr::BitAnd(l, r) => ("&", [l, r]),
+        Expr::BitOr(l, r) => ("|", [l, r]),
+        Expr::NullCoalesce(l, r) => ("??", [l, r]),
+        Expr::Equality(l, r) => ("==", [l, r]),
+        Expr::DisEquality(l, r) => ("!=", [l, r]),
+        Expr::LessThan(l, r) => ("<", [l, r]),
+        Expr::LessThanOrEqual(l, r) => ("<=", [l, r]),
+        Expr::GreaterThan(l, r) => (">", [l, r]),
+        Expr::GreaterThanOrEqual(l, r) => (">=", [l, r]),
+        Expr::ContainedIn(l, r) => ("in", [l, r]),
+        Expr::Range(l, r) => ("..", [l, r]),
+        Expr::Index(l, r) => ("[]", [l, r]),
+        Expr::Not(operand) | Expr::Negate(operand) => {
+            let operator = if matches!(expr, Expr::Not(_)) {
+                "!"
+            } else {
+                "-"
+            };
+            push_line(out, depth, operator);
+            return format_expr(out, depth + 1, operand);
+        }
+        Expr::TermWrapper(term) => return format_term(out, depth, term),
+    };
+    push_line(out, depth, operator);
+    for operand in operands {
+        format_expr(out, depth + 1, operand);
+    }
+}
+
+fn format_term(out: &mut String, depth: usize, term: &Term) {
+    match term {
+        Term::Integer(n) => push_line(out, depth, &n.to_string()),
+        Term::Float(x) => push_line(out, depth, &format!("{x:?}")),
+        Term::String(s) => push_line(out, depth, &format!("{s:?}")),
+        Term::Boolean(b) => push_line(out, depth, &b.to_string()),
+        Term::Null => push_line(out, depth, "null"),
+        Term::Variable(name) => push_line(out, depth, name),
+        Term::VariableIndexed(name, index) => {
+            push_line(out, depth, &format!("{name}[]"));
+            format_expr(out, depth + 1, index);
+        }
+        Term::Call(name, arguments) => format_exprs(out, depth, &format!("{name}()"), arguments),
+        Term::ArrayLiteral(elements) => format_exprs(out, depth, "[]", elements),
+        Term::MapLiteral(entries) => {
+            push_line(out, depth, "{}");
+            for (key, value) in entries {
+                push_line(out, depth + 1, ":");
+                format_expr(out, depth + 2, key);
+                format_expr(out, depth + 2, value);
+            }
+        }
+        Term::Block(statements) => {
+            push_line(out, depth, "block");
+            for statement in statements {
+                format_statement(out, depth + 1, statement);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer, parser};
+
+    fn format(source: &str) -> String {
+        let program = parser::parse_program(lexer::parse_with_positions(source).unwrap()).unwrap();
+        format_program(&program)
+    }
+
+    #[test]
+    fn test_format_program() {
+        let source = r#"
+            let x := 1 + 2 * -3;
+            fn double(n) { return n * 2; }
+            if x > 0 && !false { print double(x), [1, "a"]; } else { x := a[0]; }
+            for i in 0..x { }
+        "#;
+        let expected = r#"let x :=
+  +
+    1
+    *
+      2
+      -
+        3
+fn double(n)
+  block
+    return
+      *
+        n
+        2
+if
+  &&
+    >
+      x
+      0
+    !
+      false
+  block
+    print
+      double()
+        x
+      []
+        1
+        "a"
+else
+  block
+    x :=
+      a[]
+        0
+for i
+  0
+  x
+  block
+"#;
+        assert_eq!(format(source), expected);
+    }
+}
@@ -0,0 +1,538 @@
+use crate::runtime::Value;
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::{env, io};
+use unicase::UniCase;
+use unicode_normalization::UnicodeNormalization;
+
+/// Calls the built-in function `name` with its already evaluated arguments.
+pub fn call(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    match name {
+        "take" => take_or_drop(name, arguments, true),
+        "drop" => take_or_drop(name, arguments, false),
+        "frequencies" => frequencies(name, arguments),
+        "len" => len(name, arguments),
+        "typeof" => type_of(name, arguments),
+        "to_int" => to_int(name, arguments),
+        "to_string" => to_string(name, arguments),
+        "ord" => ord(name, arguments),
+        "chr" => chr(name, arguments),
+        "min" => min_or_max(name, arguments, Ordering::Less),
+        "max" => min_or_max(name, arguments, Ordering::Greater),
+        "abs" => abs(name, arguments),
+        "div" => div(name, arguments),
+        "input" => input(name, arguments),
+        "normalize" => normalize(name, arguments),
+        "equals_ignore_case" => equals_ignore_case(name, arguments),
+        "matches" => matches(name, arguments),
+        "find_all" => find_all(name, arguments),
+        "capture" => capture(name, arguments),
+        "getenv" => getenv(name, arguments),
+        "env" => env_var(name, arguments),
+        "setenv" => setenv(name, arguments),
+        _ => bail!("Error: unknown function {name:?}"),
+    }
+}
+
+fn expect_arguments<const N: usize>(name: &str, arguments: Vec<Value>) -> Result<[Value; N]> {
+    let count = arguments.len();
+    arguments
+        .try_into()
+        .map_err(|_| anyhow!("Error: {name} expects {N} arguments, received {count}"))
+}
+
+/// `len(x)` is the number of characters in the string `x`.
+fn len(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    match expect_arguments(name, arguments)? {
+        [Value::String(s)] => Ok(Value::Number(s.chars().count() as i64)),
+        [value] => bail!("Error: {name} expects a string, received {value:?}"),
+    }
+}
+
+/// `typeof(x)` is the name of the type of `x`, e.g. `"number"` or `"string"`.
+fn type_of(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [value] = expect_arguments(name, arguments)?;
+    Ok(Value::from(value.type_name()))
+}
+
+/// `to_int(x)` parses the string `x` as a number. Floats are truncated towards zero.
+fn to_int(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    match expect_arguments(name, arguments)? {
+        [Value::Number(n)] => Ok(Value::Number(n)),
+        [Value::String(s)] => s
+            .trim()
+            .parse()
+            .map(Value::Number)
+            .with_context(|| format!("Error: {name}: {s:?} is not a number")),
+        // The range check is needed since `as` saturates.
+        [Value::Float(x)] if x.is_finite() && x.abs() < i64::MAX as f64 => {
+            Ok(Value::Number(x.trunc() as i64))
+        }
+        [value] => bail!("Error: {name} can't convert {value:?} to a number"),
+    }
+}
+
+/// `to_string(x)` is `x` as `print` would show it.
+fn to_string(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [value] = expect_arguments(name, arguments)?;
+    Ok(Value::from(value.to_string()))
+}
+
+/// `ord(c)` is the Unicode code point of the first character of `c`.
+fn ord(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [Value::String(s)] = expect_arguments(name, arguments)? else {
+        bail!("Error: {name} expects a string");
+    };
+    let ch = s
+        .chars()
+        .next()
+        .with_context(|| format!("Error: {name} of an empty string"))?;
+    Ok(Value::Number(ch as i64))
+}
+
+/// `chr(n)` is the character with the Unicode code point `n`, the inverse of `ord`.
+fn chr(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [Value::Number(n)] = expect_arguments(name, arguments)? else {
+        bail!("Error: {name} expects a number");
+    };
+    let ch = u32::try_from(n)
+        .ok()
+        .and_then(char::from_u32)
+        .with_context(|| format!("Error: {n} is not a valid code point"))?;
+    Ok(Value::from(ch.to_string()))
+}
+
+/// `min(a, b)` is the smaller of the numbers `a` and `b`, `max(a, b)` the larger one.
+/// A number and a float are compared, and returned, as floats.
+fn min_or_max(name: &str, arguments: Vec<Value>, keep: Ordering) -> Result<Value> {
+    Ok(match expect_arguments(name, arguments)? {
+        [Value::Number(a), Value::Number(b)] => {
+            Value::Number(if a.cmp(&b) == keep { a } else { b })
+        }
+        [a @ (Value::Number(_) | Value::Float(_)), b @ (Value::Number(_) | Value::Float(_))] => {
+            let as_float = |value| match value {
+                Value::Number(n) => n as f64,
+                Value::Float(x) => x,
+                _ => unreachable!("checked above"),
+            };
+            let (a, b) = (as_float(a), as_float(b));
+            Value::Float(if a.total_cmp(&b) == keep { a } else { b })
+        }
+        [a, b] => bail!("Error: {name} expects two numbers, received {a:?} and {b:?}"),
+    })
+}
+
+/// `abs(n)` is the absolute value of the number `n`.
+fn abs(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    match expect_arguments(name, arguments)? {
+        [Value::Number(n)] => n
+            .checked_abs()
+            .map(Value::Number)
+            .context("Error: arithmetic overflow"),
+        [Value::Float(x)] => Ok(Value::Float(x.abs())),
+        [value] => bail!("Error: {name} expects a number, received {value:?}"),
+    }
+}
+
+/// `div(a, b)` divides `a` by `b` rounding down, even with true division on: `div(7, 2) == 3`
+/// and `div(-7, 2) == -4`. With a float operand the result is a whole float.
+fn div(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    match expect_arguments(name, arguments)? {
+        [Value::Number(_) | Value::Float(_), Value::Number(0)] => bail!("Error: Division by zero"),
+        [Value::Number(_) | Value::Float(_), Value::Float(0.0)] => bail!("Error: Division by zero"),
+        [Value::Number(a), Value::Number(b)] => {
+            let quotient = a.checked_div(b).context("Error: arithmetic overflow")?;
+            // `/` truncates towards zero, a negative quotient with a remainder is one too high.
+            let floored = if a % b != 0 && (a < 0) != (b < 0) {
+                quotient - 1
+            } else {
+                quotient
+            };
+            Ok(Value::Number(floored))
+        }
+        [Value::Float(a), Value::Number(b)] => Ok(Value::Float((a / b as f64).floor())),
+        [Value::Number(a), Value::Float(b)] => Ok(Value::Float((a as f64 / b).floor())),
+        [Value::Float(a), Value::Float(b)] => Ok(Value::Float((a / b).floor())),
+        [a, b] => bail!("Error: {name} expects two numbers, received {a:?} and {b:?}"),
+    }
+}
+
+/// `input()` reads the next line from stdin, without its line terminator.
+/// Once stdin is exhausted it returns the empty string.
+fn input(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [] = expect_arguments(name, arguments)?;
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .context("Error: failed to read from stdin")?;
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    Ok(Value::from(line))
+}
+
+/// `getenv(name)` is the value of the environment variable `name`, or null if it's not set.
+fn getenv(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [Value::String(variable)] = expect_arguments(name, arguments)? else {
+        bail!("Error: {name} expects the name of an environment variable");
+    };
+    match env::var(&*variable) {
+        Ok(value) => Ok(Value::from(value)),
+        Err(env::VarError::NotPresent) => Ok(Value::Null),
+        Err(env::VarError::NotUnicode(_)) => {
+            bail!("Error: environment variable {variable:?} is not valid UTF-8")
+        }
+    }
+}
+
+/// `env(name)` is the value of the environment variable `name`, or the empty string if it's not
+/// set: unlike `getenv`, the result is always a string.
+fn env_var(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    match getenv(name, arguments)? {
+        Value::Null => Ok(Value::from("")),
+        value => Ok(value),
+    }
+}
+
+/// `setenv(name, value)` sets the environment variable `name`, for this program and the
+/// processes it starts.
+fn setenv(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [Value::String(variable), Value::String(value)] = expect_arguments(name, arguments)? else {
+        bail!("Error: {name} expects the name of an environment variable and a string");
+    };
+    if variable.is_empty() || variable.contains(['=', '\0']) || value.contains('\0') {
+        bail!("Error: invalid environment variable {variable:?}={value:?}");
+    }
+    env::set_var(&*variable, &*value);
+    Ok(Value::Null)
+}
+
+/// `normalize(s, form)` brings `s` to the Unicode normalization form `"nfc"` or `"nfd"`,
+/// so that canonically equivalent strings compare equal.
+fn normalize(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [Value::String(s), Value::String(form)] = expect_arguments(name, arguments)? else {
+        bail!("Error: {name} expects a string and a normalization form");
+    };
+    let normalized: String = match &*form {
+        "nfc" => s.nfc().collect(),
+        "nfd" => s.nfd().collect(),
+        form => bail!("Error: unknown normalization form {form:?}, expected \"nfc\" or \"nfd\""),
+    };
+    Ok(Value::from(normalized))
+}
+
+/// The arguments of the regex built-ins: a string and a pattern.
+fn string_and_regex(name: &str, arguments: Vec<Value>) -> Result<(Rc<str>, Regex)> {
+    let [Value::String(s), Value::String(pattern)] = expect_arguments(name, arguments)? else {
+        bail!("Error: {name} expects a string and a pattern");
+    };
+    let regex =
+        Regex::new(&pattern).map_err(|e| anyhow!("Error: invalid pattern {pattern:?}: {e}"))?;
+    Ok((s, regex))
+}
+
+/// `equals_ignore_case(a, b)` compares the strings `a` and `b` with Unicode case folding,
+/// so that `"Straße"` equals `"STRASSE"`.
+fn equals_ignore_case(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [Value::String(a), Value::String(b)] = expect_arguments(name, arguments)? else {
+        bail!("Error: {name} expects two strings");
+    };
+    Ok(Value::Boolean(UniCase::new(a) == UniCase::new(b)))
+}
+
+/// `matches(s, pattern)` is whether the regular expression `pattern` matches anywhere in `s`.
+fn matches(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let (s, regex) = string_and_regex(name, arguments)?;
+    Ok(Value::Boolean(regex.is_match(&s)))
+}
+
+/// `find_all(s, pattern)` is the array of the non-overlapping matches of `pattern` in `s`.
+fn find_all(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let (s, regex) = string_and_regex(name, arguments)?;
+    let found = regex
+        .find_iter(&s)
+        .map(|m| Value::from(m.as_str()))
+        .collect::<Vec<_>>();
+    Ok(Value::from(found))
+}
+
+/// `capture(s, pattern)` is the array of the groups captured by the first match of `pattern`
+/// in `s`, starting with the whole match, or null if it doesn't match.
+/// Groups that didn't take part in the match are null too.
+fn capture(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let (s, regex) = string_and_regex(name, arguments)?;
+    let Some(captures) = regex.captures(&s) else {
+        return Ok(Value::Null);
+    };
+    let groups = captures
+        .iter()
+        .map(|group| group.map_or(Value::Null, |group| Value::from(group.as_str())))
+        .collect::<Vec<_>>();
+    Ok(Value::from(groups))
+}
+
+/// `take(x, n)` keeps the first `n` characters or elements of `x`, `drop(x, n)` everything after them.
+/// `n` is clamped to the length of `x`.
+fn take_or_drop(name: &str, arguments: Vec<Value>, take: bool) -> Result<Value> {
+    let [value, count] = expect_arguments(name, arguments)?;
+    let count = match count {
+        Value::Number(n) if n >= 0 => n as usize,
+        Value::Number(n) => bail!("Error: {name} count must not be negative, received {n}"),
+        count => bail!("Error: {name} count must be a number, received {count:?}"),
+    };
+    match value {
+        Value::String(s) if take => Ok(Value::from(s.chars().take(count).collect::<String>())),
+        Value::String(s) => Ok(Value::from(s.chars().skip(count).collect::<String>())),
+        Value::Array(elements) => {
+            let count = count.min(elements.len());
+            let kept = if take {
+                &elements[..count]
+            } else {
+                &elements[count..]
+            };
+            Ok(Value::from(kept.to_vec()))
+        }
+        value => bail!("Error: {name} expects a string or an array, received {value:?}"),
+    }
+}
+
+/// `frequencies(arr)` maps each distinct element of `arr` to how many times it occurs.
+/// Map keys are strings, so `1` and `"1"` are counted together.
+fn frequencies(name: &str, arguments: Vec<Value>) -> Result<Value> {
+    let [Value::Array(elements)] = expect_arguments(name, arguments)? else {
+        bail!("Error: {name} expects an array");
+    };
+    let mut counts = HashMap::new();
+    for element in elements.iter() {
+        let key = match element {
+            Value::String(s) => s.to_string(),
+            Value::Number(_) | Value::Boolean(_) => element.to_string(),
+            element => bail!(
+                "Error: {name} can't count a {}, only numbers, strings and booleans",
+                element.type_name()
+            ),
+        };
+        *counts.entry(key).or_insert(0) += 1;
+    }
+    let counts = counts
+        .into_iter()
+        .map(|(key, count)| (key, Value::Number(count)))
+        .collect();
+    Ok(Value::Map(Rc::new(counts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::from(s)
+    }
+
+    #[test]
+    fn test_take_drop() {
+        let take = |s, n| call("take", vec![string(s), Value::Number(n)]);
+        let drop = |s, n| call("drop", vec![string(s), Value::Number(n)]);
+        assert_eq!(take("hello", 2).unwrap(), string("he"));
+        assert_eq!(drop("hello", 2).unwrap(), string("llo"));
+        assert_eq!(take("hello", 10).unwrap(), string("hello"));
+        assert_eq!(drop("hello", 10).unwrap(), string(""));
+        assert!(take("hello", -1).is_err());
+        assert!(drop("hello", -1).is_err());
+        assert!(call("take", vec![string("hello")]).is_err());
+
+        let array = || Value::from(vec![Value::Number(1), Value::Number(2), Value::Number(3)]);
+        let take = |n| call("take", vec![array(), Value::Number(n)]);
+        let drop = |n| call("drop", vec![array(), Value::Number(n)]);
+        assert_eq!(
+            take(2).unwrap(),
+            Value::from(vec![Value::Number(1), Value::Number(2)])
+        );
+        assert_eq!(drop(2).unwrap(), Value::from(vec![Value::Number(3)]));
+        assert_eq!(take(10).unwrap(), array());
+        assert_eq!(drop(10).unwrap(), Value::from(vec![]));
+        assert!(take(-1).is_err());
+        assert!(drop(-1).is_err());
+        assert!(call("take", vec![Value::Number(1), Value::Number(1)]).is_err());
+    }
+
+    #[test]
+    fn test_env() {
+        // Names no other test uses, the environment is shared between the test threads.
+        env::set_var("BINA_TEST_ENV", "value");
+        env::remove_var("BINA_TEST_ENV_UNSET");
+        let env = |variable| call("env", vec![string(variable)]);
+        assert_eq!(env("BINA_TEST_ENV").unwrap(), string("value"));
+        assert_eq!(env("BINA_TEST_ENV_UNSET").unwrap(), string(""));
+        assert!(call("env", vec![Value::Number(1)]).is_err());
+        assert!(call("env", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_frequencies() {
+        let frequencies = |elements| call("frequencies", vec![Value::from(elements)]);
+        let counts = frequencies(vec![string("a"), string("b"), string("a")]).unwrap();
+        let expected = HashMap::from([
+            ("a".to_string(), Value::Number(2)),
+            ("b".to_string(), Value::Number(1)),
+        ]);
+        assert_eq!(counts, Value::Map(Rc::new(expected)));
+        let counts = frequencies(vec![
+            Value::Number(1),
+            Value::Boolean(true),
+            Value::Number(1),
+        ]);
+        assert_eq!(counts.unwrap().to_string(), r#"{"1": 2, "true": 1}"#);
+        assert_eq!(frequencies(vec![]).unwrap().to_string(), "{}");
+        assert!(frequencies(vec![Value::from(vec![])]).is_err());
+        assert!(call("frequencies", vec![string("a")]).is_err());
+    }
+
+    #[test]
+    fn test_typeof() {
+        let type_of = |value| call("typeof", vec![value]).unwrap();
+        assert_eq!(type_of(Value::Number(5)), string("number"));
+        assert_eq!(type_of(string("x")), string("string"));
+        assert_eq!(type_of(Value::Boolean(true)), string("boolean"));
+        assert_eq!(type_of(Value::from(vec![])), string("array"));
+        assert_eq!(type_of(Value::Null), string("null"));
+        assert!(call("typeof", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_conversions() {
+        let to_int = |value| call("to_int", vec![value]);
+        assert_eq!(to_int(string("42")).unwrap(), Value::Number(42));
+        assert_eq!(to_int(string(" -7\n")).unwrap(), Value::Number(-7));
+        assert_eq!(to_int(Value::Number(3)).unwrap(), Value::Number(3));
+        assert_eq!(to_int(Value::Float(-3.5)).unwrap(), Value::Number(-3));
+        assert!(to_int(string("x")).is_err());
+        assert!(to_int(Value::Float(f64::NAN)).is_err());
+        assert!(to_int(Value::Boolean(true)).is_err());
+
+        let to_string = |value| call("to_string", vec![value]).unwrap();
+        assert_eq!(to_string(Value::Number(42)), string("42"));
+        assert_eq!(to_string(string("x")), string("x"));
+        assert_eq!(to_string(Value::Null), string("null"));
+        assert_eq!(
+            to_string(Value::from(vec![Value::Number(1), string("a")])),
+            string("[1, \"a\"]")
+        );
+    }
+
+    #[test]
+    fn test_ord_chr() {
+        let ord = |s| call("ord", vec![string(s)]);
+        let chr = |n| call("chr", vec![Value::Number(n)]);
+        assert_eq!(ord("A").unwrap(), Value::Number(65));
+        assert_eq!(ord("abc").unwrap(), Value::Number(97));
+        assert_eq!(ord("é").unwrap(), Value::Number(233));
+        assert_eq!(chr(65).unwrap(), string("A"));
+        assert_eq!(chr(0x1F600).unwrap(), string("😀"));
+        assert!(ord("").is_err());
+        assert!(chr(-1).is_err());
+        assert!(chr(0xD800).is_err());
+        assert!(chr(0x110000).is_err());
+    }
+
+    #[test]
+    fn test_min_max_abs() {
+        let numbers = |name, a, b| call(name, vec![Value::Number(a), Value::Number(b)]).unwrap();
+        assert_eq!(numbers("min", 3, 7), Value::Number(3));
+        assert_eq!(numbers("max", 3, 7), Value::Number(7));
+        assert_eq!(numbers("min", -2, -2), Value::Number(-2));
+        assert_eq!(
+            call("max", vec![Value::Float(2.5), Value::Number(2)]).unwrap(),
+            Value::Float(2.5)
+        );
+        assert_eq!(
+            call("min", vec![Value::Float(2.5), Value::Number(2)]).unwrap(),
+            Value::Float(2.0)
+        );
+        assert!(call("min", vec![Value::Number(1), string("2")]).is_err());
+        assert!(call("max", vec![Value::Number(1)]).is_err());
+
+        assert_eq!(
+            call("abs", vec![Value::Number(-5)]).unwrap(),
+            Value::Number(5)
+        );
+        assert_eq!(
+            call("abs", vec![Value::Float(-0.5)]).unwrap(),
+            Value::Float(0.5)
+        );
+        assert!(call("abs", vec![Value::Number(i64::MIN)]).is_err());
+        assert!(call("abs", vec![string("-5")]).is_err());
+    }
+
+    #[test]
+    fn test_equals_ignore_case() {
+        let equals = |a, b| call("equals_ignore_case", vec![string(a), string(b)]).unwrap();
+        assert_eq!(equals("ABC", "abc"), Value::Boolean(true));
+        assert_eq!(equals("ABC", "abd"), Value::Boolean(false));
+        assert_eq!(equals("Ärger", "äRGER"), Value::Boolean(true));
+        assert_eq!(equals("Straße", "STRASSE"), Value::Boolean(true));
+        assert_eq!(equals("ΣΟΦΟΣ", "σοφος"), Value::Boolean(true));
+        assert!(call("equals_ignore_case", vec![string("a"), Value::Number(1)]).is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let normalize = |s, form| call("normalize", vec![string(s), string(form)]).unwrap();
+        let (composed, decomposed) = ("\u{e9}", "e\u{301}");
+        assert_ne!(string(composed), string(decomposed));
+        assert_eq!(normalize(composed, "nfc"), normalize(decomposed, "nfc"));
+        assert_eq!(normalize(composed, "nfd"), string(decomposed));
+        assert!(call("normalize", vec![string(composed), string("nfkc")]).is_err());
+        assert!(call("normalize", vec![string(composed)]).is_err());
+    }
+
+    #[test]
+    fn test_regex() {
+        let matches = |s, pattern| call("matches", vec![string(s), string(pattern)]);
+        assert_eq!(matches("abc123", r"\d+").unwrap(), Value::Boolean(true));
+        assert_eq!(matches("abcdef", r"^\d+$").unwrap(), Value::Boolean(false));
+        let err = matches("abc", "(").unwrap_err().to_string();
+        assert!(err.starts_with("Error: invalid pattern \"(\""), "{err}");
+        assert!(err.contains("unclosed group"), "{err}");
+        let found = call("find_all", vec![string("a1b22c333"), string(r"\d+")]).unwrap();
+        assert_eq!(
+            found,
+            Value::from(vec![string("1"), string("22"), string("333")])
+        );
+        let found = call("find_all", vec![string("abc"), string(r"\d")]).unwrap();
+        assert_eq!(found, Value::from(vec![]));
+        assert!(call("find_all", vec![string("abc"), Value::Number(1)]).is_err());
+    }
+
+    #[test]
+    fn test_capture() {
+        let capture = |s, pattern| call("capture", vec![string(s), string(pattern)]).unwrap();
+        assert_eq!(
+            capture("order 42 of 7", r"(\d+) of (\d+)"),
+            Value::from(vec![string("42 of 7"), string("42"), string("7")])
+        );
+        assert_eq!(
+            capture("ab", r"a(x)?b"),
+            Value::from(vec![string("ab"), Value::Null])
+        );
+        assert_eq!(capture("no digits", r"(\d+)"), Value::Null);
+        assert!(call("capture", vec![string("a"), string("(")]).is_err());
+    }
+
+    #[test]
+    fn test_len() {
+        assert_eq!(
+            call("len", vec![string("hello")]).unwrap(),
+            Value::Number(5)
+        );
+        assert_eq!(call("len", vec![string("")]).unwrap(), Value::Number(0));
+        assert_eq!(
+            call("len", vec![string("héllo")]).unwrap(),
+            Value::Number(5)
+        );
+        assert!(call("len", vec![Value::Number(5)]).is_err());
+        assert!(call("len", vec![]).is_err());
+    }
+}
@@ -1,10 +1,17 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     True,
     False,
+    Null,
     Assignment,
+    AddAssignment,
+    SubtractAssignment,
+    MultiplyAssignment,
     OpenRoundParenthesis,
     CloseRoundParenthesis,
     OpenGraphParenthesis,
@@ -12,45 +19,218 @@ pub enum Token {
     OpenSquareParenthesis,
     CloseSquareParenthesis,
     Integer(i64),
+    Float(f64),
     Identifier(String),
     String(String),
     // control
     While,
+    Repeat,
+    Do,
+    For,
+    As,
     If,
     Else,
+    Break,
+    Continue,
+    Fn,
+    Return,
     // logic
     ExclamationPoint,
-    LogicalOr, // todo: it's unsupported as I ended up not needing it.
+    LogicalOr,
+    LogicalAnd,
+    NullCoalesce,
+    BitAnd,
+    BitOr,
     // Math:
     Addition,
+    Subtraction,
     Multiplication,
+    Division,
+    Modulo,
     Semicolon,
     Equality,
     Disequality,
     Let,
+    Range,
+    Dot,
     LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
     In,
     Print,
+    EPrint,
+    Write,
+    Swap,
+    Debug,
+    Assert,
+    Comma,
+    Colon,
+}
+
+/// Where a token starts in the source, line and column are 1-based.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// The characters of the source, keeping track of the position of the next one.
+struct SourceChars<'a> {
+    chars: Peekable<Chars<'a>>,
+    position: Position,
+    /// Where the token being lexed started.
+    token_start: Position,
+    /// Whether integer literals too large for an `i64` become floats, rather than an error.
+    promote_large_integers: bool,
+}
+
+impl SourceChars<'_> {
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+    /// The character after the next one.
+    fn peek_second(&self) -> Option<char> {
+        self.chars.clone().nth(1)
+    }
+}
+
+impl Iterator for SourceChars<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        if ch == '\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+        Some(ch)
+    }
 }
 
 // should take in input the variables and functions I've seen until now.
+#[cfg(test)]
 pub fn parse(line: &str) -> Result<Vec<Token>> {
+    let tokens = parse_with_positions(line)?;
+    Ok(tokens.into_iter().map(|(token, _)| token).collect())
+}
+
+/// Like `parse`, also returning where each token starts. Errors report where the offending token starts.
+#[cfg(test)]
+pub fn parse_with_positions(source: &str) -> Result<Vec<(Token, Position)>> {
+    parse_with_options(source, false)
+}
+
+/// Like `parse_with_positions`. With `promote_large_integers`, a decimal integer literal too large
+/// for a number is lexed as a float, losing precision, rather than failing.
+pub fn parse_with_options(
+    source: &str,
+    promote_large_integers: bool,
+) -> Result<Vec<(Token, Position)>> {
+    let start = Position { line: 1, column: 1 };
+    let mut chars = SourceChars {
+        chars: source.chars().peekable(),
+        position: start,
+        token_start: start,
+        promote_large_integers,
+    };
     let mut tokens = vec![];
-    let mut chars = line.chars().peekable();
-    let index = 0;
+    lex(&mut chars, &mut tokens).map_err(|e| anyhow!("{e} at {}", chars.token_start))?;
+    Ok(tokens)
+}
+
+fn lex(chars: &mut SourceChars, tokens: &mut Vec<(Token, Position)>) -> Result<()> {
     while let Some(&c) = chars.peek() {
+        chars.token_start = chars.position;
         let token = match c {
             '0'..='9' => {
-                let mut number = 0;
+                let mut number: Option<i64> = Some(0);
+                let mut literal = String::new();
+                let mut radix = 10;
+                // `0x`, `0o` and `0b` prefixes for hexadecimal, octal and binary literals.
+                if c == '0' {
+                    literal.push(c);
+                    chars.next();
+                    if let Some(&prefix @ ('x' | 'o' | 'b')) = chars.peek() {
+                        radix = match prefix {
+                            'x' => 16,
+                            'o' => 8,
+                            _ => 2,
+                        };
+                        literal.push(prefix);
+                        chars.next();
+                    }
+                }
+                let mut has_digits = radix == 10;
+                let mut is_float = false;
                 while let Some(&digit) = chars.peek() {
-                    if digit.is_digit(10) {
-                        number = number * 10 + digit.to_digit(10).unwrap() as i64;
+                    if let Some(value) = digit.to_digit(radix) {
+                        number = number
+                            .and_then(|n| n.checked_mul(radix as i64))
+                            .and_then(|n| n.checked_add(value as i64));
+                        literal.push(digit);
+                        has_digits = true;
                         chars.next(); // Move to the next character
+                    } else if digit == '_' {
+                        // Digit separator, as in `1_000_000`.
+                        let after_digit = has_digits && !literal.ends_with('.');
+                        literal.push(digit);
+                        chars.next();
+                        if !after_digit || !chars.peek().is_some_and(|ch| ch.is_digit(radix)) {
+                            bail!("invalid number literal '{literal}', '_' must be between digits");
+                        }
+                    } else if digit == '.'
+                        && radix == 10
+                        && !is_float
+                        && chars.peek_second().is_some_and(|ch| ch.is_ascii_digit())
+                    {
+                        // A fraction, as in `3.5`. `1..5` is a range instead.
+                        literal.push(digit);
+                        chars.next();
+                        is_float = true;
                     } else {
                         break;
                     }
                 }
-                Token::Integer(number)
+                // `10abc` is almost surely a typo rather than `10` followed by `abc`, and so is
+                // a digit that's not valid in the base, as in `0b12`.
+                if !has_digits
+                    || chars
+                        .peek()
+                        .is_some_and(|&ch| ch.is_alphanumeric() || ch == '_')
+                {
+                    while let Some(&ch) = chars.peek() {
+                        if !(ch.is_alphanumeric() || ch == '_') {
+                            break;
+                        }
+                        literal.push(ch);
+                        chars.next();
+                    }
+                    bail!("invalid number literal '{literal}'");
+                }
+                match number {
+                    _ if is_float => {
+                        let digits = literal.replace('_', "");
+                        Token::Float(digits.parse().expect("a valid float literal"))
+                    }
+                    Some(number) => Token::Integer(number),
+                    None if chars.promote_large_integers && radix == 10 => {
+                        let digits = literal.replace('_', "");
+                        Token::Float(digits.parse().expect("a valid float literal"))
+                    }
+                    None => bail!(
+                        "number literal '{literal}' is too large, the maximum is {}",
+                        i64::MAX
+                    ),
+                }
             }
             '(' => {
                 chars.next();
@@ -68,7 +248,8 @@ pub fn parse(line: &str) -> Result<Vec<Token>> {
                         chars.next();
                         Token::Equality
                     }
-                    _ => bail!("Syntax error: expected '=' after '=' on line '{line}'."),
+                    // A lone `=` is almost always a mistyped assignment.
+                    _ => bail!("Syntax error: unexpected '=', did you mean ':=' for assignment?"),
                 }
             }
             '|' => {
@@ -79,9 +260,17 @@ pub fn parse(line: &str) -> Result<Vec<Token>> {
                         chars.next();
                         Token::LogicalOr
                     }
-                    _ => {
-                        bail!("Syntax error: expected '|' after '|' on line '{line}'.");
+                    _ => Token::BitOr,
+                }
+            }
+            '&' => {
+                chars.next();
+                match chars.peek() {
+                    Some(&'&') => {
+                        chars.next();
+                        Token::LogicalAnd
                     }
+                    _ => Token::BitAnd,
                 }
             }
             '!' => {
@@ -92,26 +281,78 @@ pub fn parse(line: &str) -> Result<Vec<Token>> {
                         chars.next();
                         Token::Disequality
                     }
-                    _ => {
-                        bail!("Syntax error: unexpect char after !, : {next_char:?}")
+                    _ => Token::ExclamationPoint,
+                }
+            }
+            '+' | '-' | '*' => {
+                chars.next();
+                let assignment = chars.peek() == Some(&'=');
+                if assignment {
+                    chars.next();
+                }
+                match (c, assignment) {
+                    ('+', false) => Token::Addition,
+                    ('+', true) => Token::AddAssignment,
+                    // Whether it's a subtraction or a negation is up to the parser.
+                    ('-', false) => Token::Subtraction,
+                    ('-', true) => Token::SubtractAssignment,
+                    (_, false) => Token::Multiplication,
+                    (_, true) => Token::MultiplyAssignment,
+                }
+            }
+            '?' => {
+                chars.next();
+                match chars.peek() {
+                    Some(&'?') => {
+                        chars.next();
+                        Token::NullCoalesce
                     }
+                    _ => bail!("Syntax error: expected '?' after '?'"),
                 }
             }
-            '+' => {
+            '%' => {
                 chars.next();
-                Token::Addition
+                Token::Modulo
             }
-            '*' => {
+            '.' => {
                 chars.next();
-                Token::Multiplication
+                match chars.peek() {
+                    Some(&'.') => {
+                        chars.next();
+                        Token::Range
+                    }
+                    // Fractions need a digit before the dot, `.10` is most likely a mistyped range.
+                    Some(ch) if ch.is_ascii_digit() => {
+                        bail!("Syntax error: unexpected '.' before a digit, did you mean '..'?")
+                    }
+                    _ => Token::Dot,
+                }
             }
             ';' => {
                 chars.next();
                 Token::Semicolon
             }
+            ',' => {
+                chars.next();
+                Token::Comma
+            }
             '<' => {
                 chars.next();
-                Token::LessThan
+                if chars.peek() == Some(&'=') {
+                    chars.next();
+                    Token::LessThanOrEqual
+                } else {
+                    Token::LessThan
+                }
+            }
+            '>' => {
+                chars.next();
+                if chars.peek() == Some(&'=') {
+                    chars.next();
+                    Token::GreaterThanOrEqual
+                } else {
+                    Token::GreaterThan
+                }
             }
             '{' => {
                 chars.next();
@@ -137,28 +378,68 @@ pub fn parse(line: &str) -> Result<Vec<Token>> {
                         chars.next();
                         Token::Assignment
                     }
-                    _ => {
-                        bail!("Syntax error: expected '=' after ':' on line '{line}'.");
-                    }
+                    // Between the key and the value in a map literal.
+                    _ => Token::Colon,
                 }
             }
             ' ' | '\t' | '\n' | '\r' => {
                 chars.next();
                 continue;
             }
+            '/' => {
+                chars.next();
+                match chars.peek() {
+                    // `// ...` lasts until the end of the line.
+                    Some(&'/') => {
+                        for ch in chars.by_ref() {
+                            if ch == '\n' {
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                    // `/* ... */` can span multiple lines.
+                    Some(&'*') => {
+                        chars.next();
+                        let mut previous = None;
+                        loop {
+                            match chars.next() {
+                                Some('/') if previous == Some('*') => break,
+                                Some(ch) => previous = Some(ch),
+                                None => bail!("unterminated block comment"),
+                            }
+                        }
+                        continue;
+                    }
+                    _ => Token::Division,
+                }
+            }
             '"' => {
                 let mut string = String::new();
+                let mut terminated = false;
                 chars.next();
-                while let Some(&ch) = chars.peek() {
-                    if ch == '"' {
-                        chars.next();
-                        break;
-                    } else {
-                        string.push(ch);
-                        chars.next();
+                while let Some(ch) = chars.next() {
+                    match ch {
+                        '"' => {
+                            terminated = true;
+                            break;
+                        }
+                        '\\' => string.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some('"') => '"',
+                            Some('\\') => '\\',
+                            Some(other) => bail!("unknown escape sequence '\\{other}'"),
+                            None => break,
+                        }),
+                        ch => string.push(ch),
                     }
                 }
-                Token::String(string.replace("\\n", "\n"))
+                if !terminated {
+                    bail!("unterminated string literal");
+                }
+                Token::String(string)
             }
             'a'..='z' | 'A'..='Z' | '_' => {
                 let mut identifier = String::new();
@@ -172,37 +453,58 @@ pub fn parse(line: &str) -> Result<Vec<Token>> {
                 }
                 match identifier.as_str() {
                     "while" => Token::While,
+                    "repeat" => Token::Repeat,
+                    "do" => Token::Do,
+                    "for" => Token::For,
+                    "as" => Token::As,
                     "if" => Token::If,
                     "else" => Token::Else,
+                    "break" => Token::Break,
+                    "continue" => Token::Continue,
+                    "fn" => Token::Fn,
+                    "return" => Token::Return,
                     "true" => Token::True,
                     "false" => Token::False,
+                    "null" => Token::Null,
                     "let" => Token::Let,
                     "in" => Token::In,
                     "print" => Token::Print,
+                    "eprint" => Token::EPrint,
+                    "write" => Token::Write,
+                    "swap" => Token::Swap,
+                    "debug" => Token::Debug,
+                    "assert" => Token::Assert,
+                    // `_5` would otherwise be a variable, while it's most likely a mistyped number.
+                    _ if identifier.starts_with('_')
+                        && identifier
+                            .chars()
+                            .all(|ch| ch == '_' || ch.is_ascii_digit())
+                        && identifier.chars().any(|ch| ch.is_ascii_digit()) =>
+                    {
+                        bail!("invalid number literal '{identifier}', '_' must be between digits")
+                    }
                     _ => Token::Identifier(identifier),
                 }
             }
             _ => {
-                bail!("Error, unrecognized char: {c} on line '{line}'");
+                bail!("Error, unrecognized char: {c}");
             }
         };
-        tokens.push(token);
+        tokens.push((token, chars.token_start));
     }
-    Ok(tokens)
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use crate::lexer::Token::{
-        Assignment, CloseGraphParenthesis, Identifier, Let, OpenGraphParenthesis, True,
-    };
-    use crate::lexer::{parse, Token};
-    use std::{assert_eq, matches, println, vec};
+    use crate::lexer::Token::{Assignment, CloseGraphParenthesis, Let, OpenGraphParenthesis, True};
+    use crate::lexer::{parse, parse_with_options, parse_with_positions, Token};
+    use std::{assert_eq, println, vec};
 
     fn expect_single_number(line: &str, expected: Token) {
         let tokens = parse(line).unwrap();
         assert_eq!(tokens.len(), 1);
-        assert!(matches!(&tokens[0], expected));
+        assert_eq!(tokens[0], expected);
     }
     #[test]
     fn test_number_parsing() {
@@ -243,7 +545,314 @@ mod test {
     #[test]
     fn test_keywords_identifiers_parsing() {
         let line = "while ";
+        assert_eq!(parse(line).unwrap(), vec![Token::While]);
+    }
+
+    #[test]
+    fn test_assignment_typo() {
+        let err = parse("x = 5;").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("did you mean ':=' for assignment?"));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        use Token::{GreaterThan, GreaterThanOrEqual, Integer, LessThan, LessThanOrEqual};
+        assert_eq!(
+            parse("3 <= 3").unwrap(),
+            vec![Integer(3), LessThanOrEqual, Integer(3)]
+        );
+        assert_eq!(
+            parse("5>=2").unwrap(),
+            vec![Integer(5), GreaterThanOrEqual, Integer(2)]
+        );
+        assert_eq!(
+            parse("1 < 2 > 0").unwrap(),
+            vec![Integer(1), LessThan, Integer(2), GreaterThan, Integer(0)]
+        );
+    }
+
+    #[test]
+    fn test_number_overflow() {
+        expect_single_number("9223372036854775807", Token::Integer(i64::MAX));
+        let err = parse("12345678901234567890").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("number literal '12345678901234567890' is too large"));
+        assert!(parse("9223372036854775808").is_err());
+
+        let promote = |source| parse_with_options(source, true).map(|tokens| tokens[0].0.clone());
+        assert_eq!(
+            promote("12345678901234567890").unwrap(),
+            Token::Float(12345678901234567890.0)
+        );
+        assert_eq!(
+            promote("9223372036854775807").unwrap(),
+            Token::Integer(i64::MAX)
+        );
+        assert!(promote("0xFFFF_FFFF_FFFF_FFFF").is_err());
+    }
+
+    #[test]
+    fn test_digit_separators() {
+        expect_single_number("1_000_000", Token::Integer(1000000));
+        expect_single_number("1_2_3", Token::Integer(123));
+        let err = parse("1__0").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid number literal '1_', '_' must be between digits at line 1, column 1"
+        );
+        assert!(parse("1_").is_err());
+        assert!(parse("1_;").is_err());
+        assert!(parse("_5").is_err());
+        assert_eq!(
+            parse("_a5").unwrap(),
+            vec![Token::Identifier("_a5".to_string())]
+        );
     }
+
+    #[test]
+    fn test_radix_prefixes() {
+        expect_single_number("0xFF", Token::Integer(255));
+        expect_single_number("0xff", Token::Integer(255));
+        expect_single_number("0b1010", Token::Integer(10));
+        expect_single_number("0o17", Token::Integer(15));
+        expect_single_number("0x7fff_ffff_ffff_ffff", Token::Integer(i64::MAX));
+        expect_single_number("0", Token::Integer(0));
+        expect_single_number("007", Token::Integer(7));
+        let err = parse("0xZZ").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid number literal '0xZZ' at line 1, column 1"
+        );
+        assert!(parse("0b102").is_err());
+        assert!(parse("0o8").is_err());
+        assert!(parse("0x").is_err());
+        assert!(parse("0x_1").is_err());
+        assert!(parse("0x8000000000000000").is_err());
+    }
+
+    #[test]
+    fn test_number_termination() {
+        use Token::{Addition, Integer, Multiplication, Semicolon};
+        expect_single_number("123", Integer(123));
+        assert_eq!(
+            parse("123+4").unwrap(),
+            vec![Integer(123), Addition, Integer(4)]
+        );
+        assert_eq!(
+            parse("1*23;").unwrap(),
+            vec![Integer(1), Multiplication, Integer(23), Semicolon]
+        );
+    }
+
+    #[test]
+    fn test_float_literals() {
+        expect_single_number("3.5", Token::Float(3.5));
+        expect_single_number("0.10", Token::Float(0.1));
+        expect_single_number("1_000.25", Token::Float(1000.25));
+        assert_eq!(
+            parse("1.5..2").unwrap(),
+            vec![Token::Float(1.5), Token::Range, Token::Integer(2)]
+        );
+        assert!(parse("1.5.3").is_err());
+        assert!(parse("1._5").is_err());
+        assert!(parse("2.5x").is_err());
+    }
+
+    #[test]
+    fn test_range() {
+        use Token::{Dot, Identifier, In, Integer, Range};
+        assert_eq!(
+            parse("5 in 0..10").unwrap(),
+            vec![Integer(5), In, Integer(0), Range, Integer(10)]
+        );
+        assert!(parse(".10").is_err());
+        assert_eq!(
+            parse("1..x").unwrap(),
+            vec![Integer(1), Range, Identifier("x".to_string())]
+        );
+        assert_eq!(
+            parse("s.len").unwrap(),
+            vec![
+                Identifier("s".to_string()),
+                Dot,
+                Identifier("len".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        use Token::{
+            AddAssignment, Addition, Identifier, Integer, MultiplyAssignment, SubtractAssignment,
+            Subtraction,
+        };
+        let x = || Identifier("x".to_string());
+        assert_eq!(
+            parse("x += 1").unwrap(),
+            vec![x(), AddAssignment, Integer(1)]
+        );
+        assert_eq!(
+            parse("x -= 1").unwrap(),
+            vec![x(), SubtractAssignment, Integer(1)]
+        );
+        assert_eq!(
+            parse("x *= 1").unwrap(),
+            vec![x(), MultiplyAssignment, Integer(1)]
+        );
+        assert!(parse("x+ =1").is_err());
+        assert_eq!(
+            parse("x+-1").unwrap(),
+            vec![x(), Addition, Subtraction, Integer(1)]
+        );
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        use Token::{BitAnd, BitOr, Identifier, LogicalAnd, LogicalOr};
+        let a = || Identifier("a".to_string());
+        let b = || Identifier("b".to_string());
+        assert_eq!(parse("a && b").unwrap(), vec![a(), LogicalAnd, b()]);
+        assert_eq!(parse("a || b").unwrap(), vec![a(), LogicalOr, b()]);
+        assert_eq!(parse("a & b").unwrap(), vec![a(), BitAnd, b()]);
+        assert_eq!(parse("a | b").unwrap(), vec![a(), BitOr, b()]);
+        assert_eq!(
+            parse("a&&b&b").unwrap(),
+            vec![a(), LogicalAnd, b(), BitAnd, b()]
+        );
+        assert_eq!(parse("a|||b").unwrap(), vec![a(), LogicalOr, BitOr, b()]);
+    }
+
+    #[test]
+    fn test_null_coalesce() {
+        use Token::{Identifier, Integer, Null, NullCoalesce};
+        assert_eq!(
+            parse("a ?? null ?? 5").unwrap(),
+            vec![
+                Identifier("a".to_string()),
+                NullCoalesce,
+                Null,
+                NullCoalesce,
+                Integer(5)
+            ]
+        );
+        assert!(parse("a ? 5").is_err());
+    }
+
+    #[test]
+    fn test_not() {
+        use Token::{Disequality, ExclamationPoint, False, Integer};
+        assert_eq!(parse("!false").unwrap(), vec![ExclamationPoint, False]);
+        assert_eq!(
+            parse("1 != 2").unwrap(),
+            vec![Integer(1), Disequality, Integer(2)]
+        );
+    }
+
+    #[test]
+    fn test_modulo() {
+        use Token::{Integer, Modulo};
+        assert_eq!(
+            parse("10 % 3").unwrap(),
+            vec![Integer(10), Modulo, Integer(3)]
+        );
+    }
+    #[test]
+    fn test_number_followed_by_identifier() {
+        let err = parse("let a := 10abc;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid number literal '10abc' at line 1, column 10"
+        );
+        assert!(parse("10_").is_err());
+        assert_eq!(
+            parse("10 abc").unwrap(),
+            vec![Token::Integer(10), Token::Identifier("abc".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let expected = parse("let x := 1;").unwrap();
+        assert_eq!(parse("let x := 1; // comment").unwrap(), expected);
+        assert_eq!(parse("// comment\nlet x := 1;\n//").unwrap(), expected);
+        assert_eq!(
+            parse("let x /* a\n** multiline */ := 1;/**/").unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse("let x := \"// not a comment\";").unwrap()[3],
+            Token::String("// not a comment".to_string())
+        );
+
+        let err = parse("let x := 1; /* comment").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unterminated block comment at line 1, column 13"
+        );
+        assert!(parse("/*/").is_err());
+        use Token::{Division, Integer};
+        assert_eq!(
+            parse("1 / 2").unwrap(),
+            vec![Integer(1), Division, Integer(2)]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_string() {
+        let err = parse("\"abc").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unterminated string literal at line 1, column 1"
+        );
+        assert!(parse("print \"").is_err());
+        assert_eq!(parse("\"\"").unwrap(), vec![Token::String(String::new())]);
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        let string = |s: &str| vec![Token::String(s.to_string())];
+        assert_eq!(parse(r#""a\tb""#).unwrap(), string("a\tb"));
+        assert_eq!(parse(r#""a\nb\r""#).unwrap(), string("a\nb\r"));
+        assert_eq!(parse(r#""say \"hi\"""#).unwrap(), string("say \"hi\""));
+        assert_eq!(parse(r#""C:\\new""#).unwrap(), string("C:\\new"));
+        let err = parse(r#""\q""#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown escape sequence '\\q' at line 1, column 1"
+        );
+        assert!(parse(r#""abc\""#).is_err());
+    }
+
+    #[test]
+    fn test_positions() {
+        let tokens = parse_with_positions("let a := 1;\n  print \"x\ty\";").unwrap();
+        let positions: Vec<_> = tokens
+            .iter()
+            .map(|(_, position)| (position.line, position.column))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 10),
+                (1, 11),
+                (2, 3),
+                (2, 9),
+                (2, 14)
+            ]
+        );
+        let err = parse("let a := 1;\nlet b := 2 $ 3;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error, unrecognized char: $ at line 2, column 12"
+        );
+    }
+
     #[test]
     fn test_parse_line() {
         let program = r#"      
@@ -1,29 +1,177 @@
 extern crate core;
 
+mod ast_printer;
+mod builtins;
 mod lexer;
 mod parser;
+mod repl;
 mod runtime;
 
-use crate::runtime::run;
-use anyhow::{bail, Context, Result};
-use std::{env, fs};
+use crate::parser::Statement;
+use crate::runtime::{run, Interpreter, RunOptions};
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use std::process::ExitCode;
+use std::{env, fs, io, thread};
 
-fn main() -> Result<()> {
+const USAGE: &str =
+    "Usage: bina [--max-output <bytes>] [--profile] [--strict-types | --no-coerce] [--true-division] [--promote-large-integers] [--continue-on-error] [--ast | --tokens | --check] [--prompt <text>] [-e <program> | <filename> | <directory>] [--] [<args>...]";
+
+/// The evaluation recurses for every nested call, a debug build needs tens of KB of stack for
+/// each. Enough for the interpreter's call depth limit to kick in before the stack runs out.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+fn main() -> ExitCode {
     env_logger::init();
-    let args: Vec<String> = env::args().collect();
+    let result = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run_main)
+        .expect("Error starting the interpreter thread")
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+    // `{:#}` puts the whole chain of contexts on one line, without a backtrace.
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_main() -> Result<()> {
+    let mut options = RunOptions::default();
+    let mut filename = None;
+    let mut source = None;
+    let mut prompt = repl::DEFAULT_PROMPT.to_string();
+    let mut mode = Mode::Run;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-output" => {
+                let max_bytes = args.next().context(USAGE)?;
+                let max_bytes = max_bytes.parse().context("Invalid --max-output value")?;
+                options.max_output_bytes = Some(max_bytes);
+            }
+            "--profile" => options.profile = true,
+            "--strict-types" | "--no-coerce" => options.strict_types = true,
+            "--true-division" => options.true_division = true,
+            "--promote-large-integers" => options.promote_large_integers = true,
+            "--continue-on-error" => options.continue_on_error = true,
+            "--ast" => mode = Mode::PrintAst,
+            "--tokens" => mode = Mode::PrintTokens,
+            "--check" => mode = Mode::Check,
+            "--prompt" => prompt = args.next().context(USAGE)?,
+            "-e" => source = Some(args.next().context(USAGE)?),
+            // Interpreter flags end at `--` or at the program, the rest is for `args()`
+            "--" => break,
+            _ if source.is_some() => {
+                options.script_args.push(arg);
+                break;
+            }
+            _ => {
+                filename = Some(arg);
+                break;
+            }
+        }
+    }
+    if source.is_none() && filename.is_none() {
+        filename = args.next();
+    }
+    let mut args = args.peekable();
+    // `bina prog.bina -- foo` passes just `foo`, as `bina -e <program> -- foo` does.
+    if filename.is_some() && args.peek().is_some_and(|arg| arg == "--") {
+        args.next();
+    }
+    options.script_args.extend(args);
 
-    // Check if an argument is provided
-    if args.len() < 2 {
-        bail!("Usage: bina <filename>");
+    let contents = match (source, filename) {
+        (Some(source), _) => source,
+        // A directory is a suite of scripts, run one after the other
+        (None, Some(filename)) if matches!(mode, Mode::Run) && Path::new(&filename).is_dir() => {
+            return run_directory(Path::new(&filename), options);
+        }
+        // Read the file specified in the arguments
+        (None, Some(filename)) => read_source(Path::new(&filename))?,
+        // Without a program to run, start an interactive session
+        (None, None) => {
+            let mut interpreter = Interpreter::new(options);
+            // Straight from `stdin()`, whose buffer `input()` shares, without a lock or a
+            // buffer of its own that would take the lines meant for `input()`.
+            let read_line = |line: &mut String| io::stdin().read_line(line);
+            repl::run(&mut interpreter, read_line, io::stdout(), &prompt)?;
+            return interpreter.report_profile();
+        }
+    };
+    match mode {
+        Mode::Run => run_source(&contents, options),
+        Mode::PrintTokens => {
+            let tokens = lexer::parse_with_options(&contents, options.promote_large_integers)?;
+            for (token, position) in tokens {
+                println!("{}:{} {token:?}", position.line, position.column);
+            }
+            Ok(())
+        }
+        Mode::PrintAst => {
+            let program = parse_source(&contents, &options)?;
+            print!("{}", ast_printer::format_program(&program));
+            Ok(())
+        }
+        Mode::Check => parse_source(&contents, &options).map(|_| ()),
     }
+}
 
-    // Read the file specified in the first argument
-    let filename = &args[1];
-    let contents = fs::read_to_string(filename).context("Error reading input file")?;
-    let tokens = lexer::parse(&contents)?;
-    //dbg!(&tokens);
-    let parsed = parser::parse_input(tokens)?;
-    //dbg!(&parsed);
-    run(parsed)?;
+/// What to do with the program, the interpreter runs it unless a flag says otherwise.
+enum Mode {
+    Run,
+    /// `--tokens`: print the output of the lexer.
+    PrintTokens,
+    /// `--ast`: print the parsed program.
+    PrintAst,
+    /// `--check`: only report syntax errors, the exit code tells whether there are any.
+    Check,
+}
+
+fn read_source(path: &Path) -> Result<String> {
+    let contents = fs::read(path).context("Error reading input file")?;
+    String::from_utf8(contents).context("input file is not valid UTF-8")
+}
+
+fn parse_source(contents: &str, options: &RunOptions) -> Result<Vec<Statement>> {
+    let tokens = lexer::parse_with_options(contents, options.promote_large_integers)?;
+    // Every syntax error gets reported, not just the first one.
+    parser::parse_program_recovering(tokens).map_err(|errors| {
+        let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        anyhow!(errors.join("\n"))
+    })
+}
+
+fn run_source(contents: &str, options: RunOptions) -> Result<()> {
+    run(parse_source(contents, &options)?, options)
+}
+
+/// Runs the `*.bina` files in `directory` sorted by name, each with its own variables, with a
+/// `---` line between their outputs.
+fn run_directory(directory: &Path, options: RunOptions) -> Result<()> {
+    let mut scripts = vec![];
+    for entry in fs::read_dir(directory).context("Error reading input directory")? {
+        let path = entry.context("Error reading input directory")?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "bina")
+            && path.is_file()
+        {
+            scripts.push(path);
+        }
+    }
+    scripts.sort();
+    for (i, script) in scripts.iter().enumerate() {
+        if i > 0 {
+            println!("---");
+        }
+        read_source(script)
+            .and_then(|contents| run_source(&contents, options.clone()))
+            .with_context(|| format!("Error running {}", script.display()))?;
+    }
     Ok(())
 }
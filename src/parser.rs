@@ -1,53 +1,258 @@
-use crate::lexer::Token;
-use anyhow::{bail, Result};
-use std::iter::Peekable;
+use crate::lexer::{Position, Token};
+use anyhow::{anyhow, bail, Result};
+use log::debug;
+use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Term {
     Integer(i64),
-    String(String),
+    Float(f64),
+    /// Shared with the values it evaluates to, so evaluating a literal doesn't copy it.
+    String(Rc<str>),
     Boolean(bool),
+    Null,
     Variable(String),
     VariableIndexed(String, Box<Expr>),
+    Call(String, Vec<Expr>), // function name, arguments
+    ArrayLiteral(Vec<Expr>),
+    MapLiteral(Vec<(Expr, Expr)>), // key, value
+    /// `{ statements; expr }`: a scope whose value is the one of its last expression.
+    Block(Vec<Statement>),
 }
 #[derive(Debug, PartialEq, Clone)]
 pub enum Expr {
-    //TODO: these can be deduplicated with a binaryop
-    Add(Box<Term>, Box<Term>),
-    Multiply(Box<Term>, Box<Term>),
-    LogicalOr(Box<Term>, Box<Term>),
-    Equality(Box<Term>, Box<Term>),
-    DisEquality(Box<Term>, Box<Term>),
-    LessThan(Box<Term>, Box<Term>),
-    ContainedIn(Box<Term>, Box<Term>),
+    Add(Box<Expr>, Box<Expr>),
+    Subtract(Box<Expr>, Box<Expr>),
+    Multiply(Box<Expr>, Box<Expr>),
+    Divide(Box<Expr>, Box<Expr>),
+    Modulo(Box<Expr>, Box<Expr>),
+    LogicalOr(Box<Expr>, Box<Expr>),
+    LogicalAnd(Box<Expr>, Box<Expr>),
+    BitAnd(Box<Expr>, Box<Expr>),
+    BitOr(Box<Expr>, Box<Expr>),
+    NullCoalesce(Box<Expr>, Box<Expr>),
+    Equality(Box<Expr>, Box<Expr>),
+    DisEquality(Box<Expr>, Box<Expr>),
+    LessThan(Box<Expr>, Box<Expr>),
+    LessThanOrEqual(Box<Expr>, Box<Expr>),
+    GreaterThan(Box<Expr>, Box<Expr>),
+    GreaterThanOrEqual(Box<Expr>, Box<Expr>),
+    ContainedIn(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Range(Box<Expr>, Box<Expr>), // start (inclusive), end (exclusive)
+    Index(Box<Expr>, Box<Expr>), // indexed expression, index
     TermWrapper(Term),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
-    If(Box<Expr>, Box<Statement>),    // predicate, block
-    While(Box<Expr>, Box<Statement>), // predicate, block
+    If(Box<Expr>, Box<Statement>, Option<Box<Statement>>), // predicate, block, else
+    While(Box<Expr>, Option<String>, Box<Statement>),      // predicate, index variable, block
+    Repeat(Box<Expr>, Option<String>, Box<Statement>),     // count, index variable, block
+    DoWhile(Box<Statement>, Box<Expr>),                    // block, predicate
+    For(String, Box<Expr>, Box<Expr>, Box<Statement>),     // variable, start, end, block
+    ForEntries(String, String, Box<Expr>, Box<Statement>), // key, value, map, block
     Block(Vec<Statement>),
     Assignment(String, Box<Expr>, bool), // bool = prefixed by let or not
-    Print(Box<Expr>),
+    IndexedAssignment(String, Box<Expr>, Box<Expr>), // variable, index, value
+    Print(Vec<Expr>),
+    EPrint(Vec<Expr>), // like print, to stderr
+    Write(Vec<Expr>),  // like print, without the newline
+    Swap(String, String),
+    Debug,
+    Assert(Box<Expr>),
+    Break,
+    Continue,
+    FunctionDef(String, Vec<String>, Box<Statement>), // name, parameters, block
+    Return(Box<Expr>),
+    Expression(Box<Expr>),         // evaluated only for its side effects
+    Empty,                         // a lone `;`
+    AtLine(usize, Box<Statement>), // the line the statement starts on, for runtime errors
+}
+
+/// The tokens to parse, along with where each one starts when that's known.
+struct Tokens {
+    tokens: Vec<(Token, Option<Position>)>,
+    /// The index of the next token.
+    next: usize,
+    /// Where the last token looked at starts.
+    position: Option<Position>,
 }
-fn parse_block(input: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Statement> {
+
+impl Tokens {
+    fn new(tokens: Vec<(Token, Option<Position>)>) -> Self {
+        Tokens {
+            tokens,
+            next: 0,
+            position: None,
+        }
+    }
+    fn peek(&mut self) -> Option<&Token> {
+        let (token, position) = self.tokens.get(self.next)?;
+        self.position = *position;
+        Some(token)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let (token, position) = self.tokens.get(self.next)?.clone();
+        self.next += 1;
+        self.position = position;
+        Some(token)
+    }
+    /// Looks `n` tokens past the next one, without moving.
+    fn peek_after(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.next + n).map(|(token, _)| token)
+    }
+}
+
+#[cfg(test)]
+impl From<Vec<Token>> for Tokens {
+    fn from(tokens: Vec<Token>) -> Self {
+        Tokens::new(tokens.into_iter().map(|token| (token, None)).collect())
+    }
+}
+fn parse_block(input: &mut Tokens) -> Result<Statement> {
     let left_par = input.next();
     if left_par != Some(Token::OpenGraphParenthesis) {
         return Err(anyhow::anyhow!("Expected '{{', received: {left_par:?}."));
     }
+    parse_block_body(input).map(Statement::Block)
+}
+/// Parses the statements of a block up to its `}`, after the `{`.
+fn parse_block_body(input: &mut Tokens) -> Result<Vec<Statement>> {
+    let opened_at = input.position;
     let mut ret = vec![];
     while input.peek() != Some(&Token::CloseGraphParenthesis) {
+        check_bracket(input.peek(), &Token::CloseGraphParenthesis, opened_at)?;
         let statement = parse_statement(input)?;
         ret.push(statement);
     }
     let _right_par = input.next();
-    Ok(Statement::Block(ret))
+    Ok(ret)
+}
+/// Parses the optional `as <identifier>` binding for the iteration index of a loop.
+fn parse_loop_index(input: &mut Tokens) -> Result<Option<String>> {
+    if input.peek() != Some(&Token::As) {
+        return Ok(None);
+    }
+    let _as = input.next();
+    expect_identifier(input.next()).map(Some)
 }
-fn parse_while(input: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Statement> {
+fn parse_while(input: &mut Tokens) -> Result<Statement> {
     let condition = parse_expr(input)?;
+    let index = parse_loop_index(input)?;
+    let block = parse_block(input)?;
+    Ok(Statement::While(
+        Box::new(condition),
+        index,
+        Box::new(block),
+    ))
+}
+fn parse_repeat(input: &mut Tokens) -> Result<Statement> {
+    let count = parse_expr(input)?;
+    let index = parse_loop_index(input)?;
     let block = parse_block(input)?;
-    Ok(Statement::While(Box::new(condition), Box::new(block)))
+    Ok(Statement::Repeat(Box::new(count), index, Box::new(block)))
+}
+/// `do { ... } while condition;` runs the block before checking the condition.
+fn parse_do_while(input: &mut Tokens) -> Result<Statement> {
+    let block = parse_block(input)?;
+    let while_token = input.next();
+    if while_token != Some(Token::While) {
+        bail!("Expected 'while' after the block of 'do', received: {while_token:?}");
+    }
+    let condition = parse_expr(input)?;
+    expect_semicolon(input.next())?;
+    Ok(Statement::DoWhile(Box::new(block), Box::new(condition)))
+}
+fn parse_for(input: &mut Tokens) -> Result<Statement> {
+    if input.peek() == Some(&Token::OpenRoundParenthesis) {
+        return parse_for_entries(input);
+    }
+    let variable = expect_identifier(input.next())?;
+    expect_for_in(input)?;
+    let Expr::Range(start, end) = parse_expr(input)? else {
+        bail!("Expected a range like 'start..end' in for loop");
+    };
+    let block = parse_block(input)?;
+    Ok(Statement::For(variable, start, end, Box::new(block)))
+}
+/// `for (key, value) in map { ... }`, after the `for`.
+fn parse_for_entries(input: &mut Tokens) -> Result<Statement> {
+    let _open = input.next();
+    let key = expect_identifier(input.next())?;
+    let comma = input.next();
+    if comma != Some(Token::Comma) {
+        bail!("Expected ',' between the key and the value variables, received: {comma:?}");
+    }
+    let value = expect_identifier(input.next())?;
+    let close = input.next();
+    if close != Some(Token::CloseRoundParenthesis) {
+        bail!("Expected ')' after the value variable, received: {close:?}");
+    }
+    expect_for_in(input)?;
+    let map = parse_expr(input)?;
+    let block = parse_block(input)?;
+    Ok(Statement::ForEntries(
+        key,
+        value,
+        Box::new(map),
+        Box::new(block),
+    ))
+}
+fn expect_for_in(input: &mut Tokens) -> Result<()> {
+    let in_token = input.next();
+    if in_token != Some(Token::In) {
+        bail!(
+            "Expected 'in' after the for loop variable, received: {:?}",
+            in_token
+        );
+    }
+    Ok(())
+}
+fn parse_function_def(input: &mut Tokens) -> Result<Statement> {
+    let name = expect_identifier(input.next())?;
+    let open = input.next();
+    if open != Some(Token::OpenRoundParenthesis) {
+        bail!("Expected '(' after function name {name:?}, received: {open:?}");
+    }
+    let mut parameters = vec![];
+    if input.peek() == Some(&Token::CloseRoundParenthesis) {
+        let _close = input.next();
+    } else {
+        loop {
+            parameters.push(expect_identifier(input.next())?);
+            match input.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::CloseRoundParenthesis) => break,
+                token => bail!("Expected ',' or ')' in function parameters, received: {token:?}"),
+            }
+        }
+    }
+    let block = parse_block(input)?;
+    Ok(Statement::FunctionDef(name, parameters, Box::new(block)))
+}
+fn parse_if(input: &mut Tokens) -> Result<Statement> {
+    let condition = parse_expr(input)?;
+    let block = parse_block(input)?;
+    let else_block = if input.peek() == Some(&Token::Else) {
+        let _else = input.next();
+        // `else if` chains are just an `if` statement as the else branch.
+        if input.peek() == Some(&Token::If) {
+            let _if = input.next();
+            Some(Box::new(parse_if(input)?))
+        } else {
+            Some(Box::new(parse_block(input)?))
+        }
+    } else {
+        None
+    };
+    Ok(Statement::If(
+        Box::new(condition),
+        Box::new(block),
+        else_block,
+    ))
 }
 fn expect_semicolon(t: Option<Token>) -> Result<()> {
     if t != Some(Token::Semicolon) {
@@ -55,24 +260,110 @@ fn expect_semicolon(t: Option<Token>) -> Result<()> {
     }
     Ok(())
 }
-fn parse_statement(input: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Statement> {
-    match input.next() {
-        Some(Token::While) => {
-            return parse_while(input);
+fn expect_identifier(t: Option<Token>) -> Result<String> {
+    match t {
+        Some(Token::Identifier(identifier)) => Ok(identifier),
+        t => bail!("Expected identifier, received: {:?}", t),
+    }
+}
+/// The comma separated values to print, up to the `;`.
+fn parse_print_arguments(input: &mut Tokens) -> Result<Vec<Expr>> {
+    let mut arguments = vec![parse_expr(input)?];
+    loop {
+        match input.next() {
+            Some(Token::Comma) => arguments.push(parse_expr(input)?),
+            token => {
+                expect_semicolon(token)?;
+                return Ok(arguments);
+            }
         }
+    }
+}
+/// The `;` can be left out before a `}`, to give a block used as a value its last expression.
+fn parse_expression_statement(expr: Expr, input: &mut Tokens) -> Result<Statement> {
+    if input.peek() != Some(&Token::CloseGraphParenthesis) {
+        expect_semicolon(input.next())?;
+    }
+    Ok(Statement::Expression(Box::new(expr)))
+}
+/// Parses a statement, wrapped in `Statement::AtLine` if its position is known.
+fn parse_statement(input: &mut Tokens) -> Result<Statement> {
+    input.peek();
+    let line = input.position.map(|position| position.line);
+    let statement = parse_bare_statement(input)?;
+    Ok(match line {
+        Some(line) => Statement::AtLine(line, Box::new(statement)),
+        None => statement,
+    })
+}
+fn parse_bare_statement(input: &mut Tokens) -> Result<Statement> {
+    // Expression statements starting with an identifier are told apart from assignments below.
+    if matches!(
+        input.peek(),
+        Some(
+            Token::Integer(_)
+                | Token::Float(_)
+                | Token::String(_)
+                | Token::True
+                | Token::Null
+                | Token::False
+                | Token::OpenRoundParenthesis
+                | Token::OpenSquareParenthesis
+                | Token::ExclamationPoint
+                | Token::Subtraction
+        )
+    ) {
+        let expr = parse_expr(input)?;
+        return parse_expression_statement(expr, input);
+    }
+    match input.next() {
+        Some(Token::While) => parse_while(input),
+        Some(Token::Repeat) => parse_repeat(input),
+        Some(Token::Do) => parse_do_while(input),
+        Some(Token::For) => parse_for(input),
 
-        Some(Token::If) => {
-            let condition = parse_expr(input)?;
-            let block = parse_block(input)?;
-            Ok(Statement::If(Box::new(condition), Box::new(block)))
+        Some(Token::If) => parse_if(input),
+        // A bare block, only to scope the variables declared in it.
+        Some(Token::OpenGraphParenthesis) => parse_block_body(input).map(Statement::Block),
+        // `x += e` is `x := x + e`.
+        Some(Token::Identifier(s))
+            if matches!(
+                input.peek(),
+                Some(Token::AddAssignment | Token::SubtractAssignment | Token::MultiplyAssignment)
+            ) =>
+        {
+            let variable = Box::new(Expr::TermWrapper(Term::Variable(s.clone())));
+            let operator = input.next();
+            let expr = Box::new(parse_expr(input)?);
+            expect_semicolon(input.next())?;
+            let expr = match operator {
+                Some(Token::AddAssignment) => Expr::Add(variable, expr),
+                Some(Token::SubtractAssignment) => Expr::Subtract(variable, expr),
+                _ => Expr::Multiply(variable, expr),
+            };
+            Ok(Statement::Assignment(s, Box::new(expr), false))
+        }
+        Some(Token::Identifier(s)) if input.peek() != Some(&Token::Assignment) => {
+            let term = parse_identifier(s, input)?;
+            if let Term::VariableIndexed(identifier, index) = &term {
+                if input.peek() == Some(&Token::Assignment) {
+                    let _assignment = input.next();
+                    let expr = parse_expr(input)?;
+                    expect_semicolon(input.next())?;
+                    return Ok(Statement::IndexedAssignment(
+                        identifier.clone(),
+                        index.clone(),
+                        Box::new(expr),
+                    ));
+                }
+            }
+            let left = parse_postfix(input, Expr::TermWrapper(term))?;
+            let expr = parse_infix(input, left, 0)?;
+            parse_expression_statement(expr, input)
         }
-        // must be an assignment.
         Some(Token::Identifier(s)) => {
             let identifier = s.to_string();
-            let assignment = input.next();
-            if assignment != Some(Token::Assignment) {
-                bail!("Expected ':=', received: {:?}", assignment);
-            }
+            let _assignment = input.next();
             let expr = parse_expr(input)?;
             let semicolon = input.next();
             expect_semicolon(semicolon)?;
@@ -81,7 +372,7 @@ fn parse_statement(input: &mut Peekable<impl Iterator<Item = Token>>) -> Result<
         Some(Token::Let) => {
             let identifier = input.next();
             if let Some(Token::Identifier(identifier)) = identifier {
-                println!("Identifier: {:?}", identifier);
+                debug!("Identifier: {:?}", identifier);
                 let assignment = input.next();
                 if assignment != Some(Token::Assignment) {
                     bail!("Expected ':=', received: {:?}", assignment);
@@ -94,33 +385,136 @@ fn parse_statement(input: &mut Peekable<impl Iterator<Item = Token>>) -> Result<
                 bail!("Expected identifier, received: {:?}", identifier);
             }
         }
-        Some(Token::Print) => {
+        Some(Token::Print) => Ok(Statement::Print(parse_print_arguments(input)?)),
+        Some(Token::EPrint) => Ok(Statement::EPrint(parse_print_arguments(input)?)),
+        Some(Token::Write) => Ok(Statement::Write(parse_print_arguments(input)?)),
+        Some(Token::Debug) => {
+            expect_semicolon(input.next())?;
+            Ok(Statement::Debug)
+        }
+        Some(Token::Assert) => {
             let expr = parse_expr(input)?;
-            let semicolon = input.next();
-            expect_semicolon(semicolon)?;
-            Ok(Statement::Print(Box::new(expr)))
+            expect_semicolon(input.next())?;
+            Ok(Statement::Assert(Box::new(expr)))
+        }
+        Some(Token::Fn) => parse_function_def(input),
+        // A bare `return;` returns null.
+        Some(Token::Return) if input.peek() == Some(&Token::Semicolon) => {
+            let _semicolon = input.next();
+            Ok(Statement::Return(Box::new(Expr::TermWrapper(Term::Null))))
+        }
+        Some(Token::Return) => {
+            let expr = parse_expr(input)?;
+            expect_semicolon(input.next())?;
+            Ok(Statement::Return(Box::new(expr)))
+        }
+        Some(Token::Break) => {
+            expect_semicolon(input.next())?;
+            Ok(Statement::Break)
+        }
+        Some(Token::Continue) => {
+            expect_semicolon(input.next())?;
+            Ok(Statement::Continue)
+        }
+        Some(Token::Semicolon) => Ok(Statement::Empty),
+        Some(Token::Swap) => {
+            let left = expect_identifier(input.next())?;
+            let comma = input.next();
+            if comma != Some(Token::Comma) {
+                bail!("Expected ',', received: {:?}", comma);
+            }
+            let right = expect_identifier(input.next())?;
+            expect_semicolon(input.next())?;
+            Ok(Statement::Swap(left, right))
         }
         token => {
             bail!("parse_statement: Unexpected token {:?}", token);
         }
     }
 }
-fn parse_term(input: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Term> {
+/// Parses comma separated expressions up to `closing`, e.g. the arguments of a call.
+fn parse_expr_list(input: &mut Tokens, closing: Token, context: &str) -> Result<Vec<Expr>> {
+    let opened_at = input.position;
+    let mut exprs = vec![];
+    if input.peek() == Some(&closing) {
+        let _close = input.next();
+        return Ok(exprs);
+    }
+    loop {
+        exprs.push(parse_expr(input)?);
+        match input.next() {
+            Some(Token::Comma) => continue,
+            Some(token) if token == closing => return Ok(exprs),
+            token => {
+                check_bracket(token.as_ref(), &closing, opened_at)?;
+                bail!("Expected ',' or {closing:?} in {context}, received: {token:?}")
+            }
+        }
+    }
+}
+/// Fails if `token` closes a different kind of bracket than `expected`, pointing at where the
+/// bracket was opened if that's known.
+fn check_bracket(
+    token: Option<&Token>,
+    expected: &Token,
+    opened_at: Option<Position>,
+) -> Result<()> {
+    let bracket = |token: &Token| match token {
+        Token::CloseRoundParenthesis => Some(')'),
+        Token::CloseSquareParenthesis => Some(']'),
+        Token::CloseGraphParenthesis => Some('}'),
+        _ => None,
+    };
+    let (Some(expected), Some(found)) = (bracket(expected), token.and_then(bracket)) else {
+        return Ok(());
+    };
+    if expected != found {
+        let opened_at = opened_at
+            .map(|position| format!(" (opened at {position})"))
+            .unwrap_or_default();
+        bail!("mismatched bracket: expected '{expected}' but found '{found}'{opened_at}");
+    }
+    Ok(())
+}
+fn expect_closing_square(token: Option<Token>, opened_at: Option<Position>) -> Result<()> {
+    match token {
+        Some(Token::CloseSquareParenthesis) => Ok(()),
+        token => {
+            check_bracket(token.as_ref(), &Token::CloseSquareParenthesis, opened_at)?;
+            bail!("expected ']', received: {token:?}")
+        }
+    }
+}
+/// Parses what follows an identifier already taken from `input`: a call, an index or nothing.
+fn parse_identifier(identifier: String, input: &mut Tokens) -> Result<Term> {
+    Ok(if input.peek() == Some(&Token::OpenRoundParenthesis) {
+        let _open = input.next().unwrap();
+        let arguments = parse_expr_list(input, Token::CloseRoundParenthesis, "function call")?;
+        Term::Call(identifier, arguments)
+    } else if input.peek() == Some(&Token::OpenSquareParenthesis) {
+        let _open = input.next().unwrap();
+        let opened_at = input.position;
+        let index = parse_expr(input)?;
+        expect_closing_square(input.next(), opened_at)?;
+        Term::VariableIndexed(identifier, Box::new(index))
+    } else {
+        Term::Variable(identifier)
+    })
+}
+fn parse_term(input: &mut Tokens) -> Result<Term> {
     Ok(match input.next() {
         Some(Token::Integer(i)) => Term::Integer(i),
-        Some(Token::String(s)) => Term::String(s.to_string()),
+        Some(Token::Float(x)) => Term::Float(x),
+        Some(Token::String(s)) => Term::String(s.into()),
         Some(Token::True) => Term::Boolean(true),
         Some(Token::False) => Term::Boolean(false),
-        Some(Token::Identifier(s)) => {
-            if input.peek() == Some(&Token::OpenSquareParenthesis) {
-                let _open = input.next().unwrap();
-                let index = parse_expr(input)?;
-                let _close = input.next().unwrap();
-                Term::VariableIndexed(s.to_string(), Box::new(index))
-            } else {
-                Term::Variable(s.to_string())
-            }
+        Some(Token::Null) => Term::Null,
+        Some(Token::Identifier(s)) => parse_identifier(s, input)?,
+        Some(Token::OpenSquareParenthesis) => {
+            let elements = parse_expr_list(input, Token::CloseSquareParenthesis, "array literal")?;
+            Term::ArrayLiteral(elements)
         }
+        Some(Token::OpenGraphParenthesis) => parse_map_or_block(input)?,
         Some(token) => {
             bail!("parse_term: Unexpected token {:?}", token);
         }
@@ -129,63 +523,284 @@ fn parse_term(input: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Term>
         }
     })
 }
-fn parse_expr(input: &mut Peekable<impl Iterator<Item = Token>>) -> Result<Expr> {
-    let left = parse_term(input)?;
-    let op = input.peek().cloned();
-    let ret = match op {
-        Some(Token::Multiplication) => {
-            let _mult = input.next().unwrap();
-            let right = parse_term(input)?;
-            Expr::Multiply(Box::new(left), Box::new(right))
-        }
-        Some(Token::Addition) => {
-            let _add = input.next().unwrap();
-            let right = parse_term(input)?;
-            Expr::Add(Box::new(left), Box::new(right))
-        }
-        Some(Token::Disequality) => {
-            let _disequality = input.next().unwrap();
-            let right = parse_term(input)?;
-            Expr::DisEquality(Box::new(left), Box::new(right))
+/// After a `{`, tells a map literal from a block by looking for the `:` after its first key.
+/// A key is a single token, so this looks at two tokens at most.
+fn parse_map_or_block(input: &mut Tokens) -> Result<Term> {
+    let is_map = match input.peek_after(0) {
+        Some(Token::CloseGraphParenthesis) => true,
+        Some(
+            Token::String(_)
+            | Token::Integer(_)
+            | Token::Float(_)
+            | Token::Identifier(_)
+            | Token::True
+            | Token::False
+            | Token::Null,
+        ) => input.peek_after(1) == Some(&Token::Colon),
+        _ => false,
+    };
+    if is_map {
+        parse_map_literal(input)
+    } else {
+        parse_block_body(input).map(Term::Block)
+    }
+}
+/// Parses the entries of a map literal, `{ key: value, ... }`, after its `{`.
+fn parse_map_literal(input: &mut Tokens) -> Result<Term> {
+    let opened_at = input.position;
+    let mut entries = vec![];
+    if input.peek() == Some(&Token::CloseGraphParenthesis) {
+        let _close = input.next();
+        return Ok(Term::MapLiteral(entries));
+    }
+    loop {
+        let key = parse_expr(input)?;
+        let colon = input.next();
+        if colon != Some(Token::Colon) {
+            bail!("Expected ':' after a map key, received: {colon:?}");
         }
-        Some(Token::Equality) => {
-            let _equality = input.next().unwrap();
-            let right = parse_term(input)?;
-            Expr::Equality(Box::new(left), Box::new(right))
+        entries.push((key, parse_expr(input)?));
+        match input.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::CloseGraphParenthesis) => return Ok(Term::MapLiteral(entries)),
+            token => {
+                check_bracket(token.as_ref(), &Token::CloseGraphParenthesis, opened_at)?;
+                bail!("Expected ',' or '}}' in map literal, received: {token:?}")
+            }
         }
-        Some(Token::LessThan) => {
-            let _lt = input.next().unwrap();
-            let right = parse_term(input)?;
-            Expr::LessThan(Box::new(left), Box::new(right))
+    }
+}
+/// Binding powers (left, right) of the binary operators: higher binds tighter.
+/// Left associativity comes from the right power being one more than the left.
+fn infix_binding_power(token: &Token) -> Option<(u8, u8)> {
+    let bp = match token {
+        Token::LogicalOr => (1, 2),
+        Token::LogicalAnd => (3, 4),
+        Token::Equality | Token::Disequality => (5, 6),
+        Token::LessThan
+        | Token::LessThanOrEqual
+        | Token::GreaterThan
+        | Token::GreaterThanOrEqual
+        | Token::In => (7, 8),
+        // Tighter than comparisons, so that `a ?? 0 < 5` compares the defaulted value.
+        Token::NullCoalesce => (9, 10),
+        // Tighter than comparisons as in Rust, so that `flags & 1 == 1` needs no parentheses.
+        Token::BitOr => (11, 12),
+        Token::BitAnd => (13, 14),
+        Token::Range => (15, 16),
+        Token::Addition | Token::Subtraction => (17, 18),
+        Token::Multiplication | Token::Division | Token::Modulo => (19, 20),
+        _ => return None,
+    };
+    Some(bp)
+}
+fn make_binary(op: Token, left: Expr, right: Expr) -> Expr {
+    let (left, right) = (Box::new(left), Box::new(right));
+    match op {
+        Token::LogicalOr => Expr::LogicalOr(left, right),
+        Token::LogicalAnd => Expr::LogicalAnd(left, right),
+        Token::BitAnd => Expr::BitAnd(left, right),
+        Token::BitOr => Expr::BitOr(left, right),
+        Token::NullCoalesce => Expr::NullCoalesce(left, right),
+        Token::Equality => Expr::Equality(left, right),
+        Token::Disequality => Expr::DisEquality(left, right),
+        Token::LessThan => Expr::LessThan(left, right),
+        Token::LessThanOrEqual => Expr::LessThanOrEqual(left, right),
+        Token::GreaterThan => Expr::GreaterThan(left, right),
+        Token::GreaterThanOrEqual => Expr::GreaterThanOrEqual(left, right),
+        Token::In => Expr::ContainedIn(left, right),
+        Token::Range => Expr::Range(left, right),
+        Token::Addition => Expr::Add(left, right),
+        Token::Subtraction => Expr::Subtract(left, right),
+        Token::Multiplication => Expr::Multiply(left, right),
+        Token::Division => Expr::Divide(left, right),
+        Token::Modulo => Expr::Modulo(left, right),
+        _ => unreachable!("make_binary: {op:?} has no binding power"),
+    }
+}
+fn parse_expr(input: &mut Tokens) -> Result<Expr> {
+    parse_expr_bp(input, 0)
+}
+/// Prefix operators bind tighter than any binary operator.
+const PREFIX_BINDING_POWER: u8 = 21;
+fn parse_primary(input: &mut Tokens) -> Result<Expr> {
+    if input.peek() == Some(&Token::ExclamationPoint) {
+        let _not = input.next().unwrap();
+        let operand = parse_expr_bp(input, PREFIX_BINDING_POWER)?;
+        return Ok(Expr::Not(Box::new(operand)));
+    }
+    // A `-` where an operand is expected is a negation, otherwise `parse_infix` takes it
+    // as a subtraction.
+    if input.peek() == Some(&Token::Subtraction) {
+        let _minus = input.next().unwrap();
+        let operand = parse_expr_bp(input, PREFIX_BINDING_POWER)?;
+        return Ok(Expr::Negate(Box::new(operand)));
+    }
+    let primary = if input.peek() != Some(&Token::OpenRoundParenthesis) {
+        Expr::TermWrapper(parse_term(input)?)
+    } else {
+        let _open = input.next().unwrap();
+        let opened_at = input.position;
+        let expr = parse_expr(input)?;
+        match input.next() {
+            Some(Token::CloseRoundParenthesis) => expr,
+            token => {
+                check_bracket(token.as_ref(), &Token::CloseRoundParenthesis, opened_at)?;
+                bail!("Expected ')' to close '(', received: {token:?}")
+            }
         }
-        Some(Token::In) => {
-            let _in = input.next().unwrap();
-            let right = parse_term(input)?;
-            Expr::ContainedIn(Box::new(left), Box::new(right))
+    };
+    parse_postfix(input, primary)
+}
+/// Parses any chain of indexing (`[i]`) and method calls (`.name(arguments)`) following `expr`.
+/// A method call is another way to write a call: `s.take(2)` is `take(s, 2)`.
+fn parse_postfix(input: &mut Tokens, mut expr: Expr) -> Result<Expr> {
+    loop {
+        expr = match input.peek() {
+            Some(Token::OpenSquareParenthesis) => {
+                let _open = input.next().unwrap();
+                let opened_at = input.position;
+                let index = parse_expr(input)?;
+                expect_closing_square(input.next(), opened_at)?;
+                Expr::Index(Box::new(expr), Box::new(index))
+            }
+            Some(Token::Dot) => {
+                let _dot = input.next().unwrap();
+                let name = expect_identifier(input.next())?;
+                match input.next() {
+                    Some(Token::OpenRoundParenthesis) => {}
+                    token => bail!("Expected '(' after method {name:?}, received: {token:?}"),
+                }
+                let mut arguments = vec![expr];
+                arguments.extend(parse_expr_list(
+                    input,
+                    Token::CloseRoundParenthesis,
+                    "function call",
+                )?);
+                Expr::TermWrapper(Term::Call(name, arguments))
+            }
+            _ => return Ok(expr),
         }
-        Some(other) => Expr::TermWrapper(left),
-        _ => {
-            bail!("parse_expr: Unexpected token {:?}", op);
+    }
+}
+/// Precedence climbing: keeps folding operators into `left` as long as they bind at least
+/// as tightly as `min_bp`.
+fn parse_expr_bp(input: &mut Tokens, min_bp: u8) -> Result<Expr> {
+    let left = parse_primary(input)?;
+    parse_infix(input, left, min_bp)
+}
+/// Continues parsing an expression whose leftmost operand has already been parsed.
+fn parse_infix(input: &mut Tokens, mut left: Expr, min_bp: u8) -> Result<Expr> {
+    loop {
+        let Some((left_bp, right_bp)) = input.peek().and_then(infix_binding_power) else {
+            if !ends_expression(input.peek()) {
+                bail!(
+                    "Unexpected token {:?} after expression",
+                    input.peek().unwrap()
+                );
+            }
+            break;
+        };
+        if left_bp < min_bp {
+            break;
         }
-    };
-    Ok(ret)
+        let op = input.next().unwrap();
+        let right = parse_expr_bp(input, right_bp)?;
+        left = make_binary(op, left, right);
+    }
+    Ok(left)
+}
+/// The tokens that can follow a complete expression, e.g. the `{` after a bare `while flag`
+/// condition. Anything else is an error here, rather than a confusing one further on.
+fn ends_expression(token: Option<&Token>) -> bool {
+    matches!(
+        token,
+        None | Some(
+            Token::Semicolon
+                | Token::Comma
+                | Token::Colon
+                | Token::As
+                | Token::OpenGraphParenthesis
+                | Token::CloseGraphParenthesis
+                | Token::CloseRoundParenthesis
+                | Token::CloseSquareParenthesis
+        )
+    )
 }
 
-pub fn parse_input(mut input: Vec<Token>) -> Result<Vec<Statement>> {
+#[cfg(test)]
+pub fn parse_input(input: Vec<Token>) -> Result<Vec<Statement>> {
+    let mut input: Tokens = input.into();
     let mut ret = vec![];
-    let mut input = input.into_iter().peekable();
     while input.peek().is_some() {
-        println!("{:?}", input.peek());
+        debug!("{:?}", input.peek());
         ret.push(parse_statement(&mut input)?);
     }
     Ok(ret)
 }
 
+/// A syntax error, with the position of the last token looked at when it's known.
+#[derive(Debug)]
+pub struct ParseError {
+    pub message: String,
+    pub position: Option<Position>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some(position) => write!(f, "{} at {position}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Like `parse_input`, with each statement in a `Statement::AtLine`. Errors report the position
+/// of the last token looked at.
+pub fn parse_program(input: Vec<(Token, Position)>) -> Result<Vec<Statement>> {
+    parse_program_recovering(input).map_err(|errors| anyhow!("{}", errors[0]))
+}
+
+/// Like `parse_program`, but after a syntax error it skips to the end of the statement (the next
+/// `;` or `}`) and goes on, so that every error gets reported at once.
+pub fn parse_program_recovering(
+    input: Vec<(Token, Position)>,
+) -> std::result::Result<Vec<Statement>, Vec<ParseError>> {
+    let tokens: Vec<_> = input
+        .into_iter()
+        .map(|(token, position)| (token, Some(position)))
+        .collect();
+    let mut tokens = Tokens::new(tokens);
+    let mut statements = vec![];
+    let mut errors = vec![];
+    while tokens.peek().is_some() {
+        match parse_statement(&mut tokens) {
+            Ok(statement) => statements.push(statement),
+            Err(e) => {
+                errors.push(ParseError {
+                    message: e.to_string(),
+                    position: tokens.position,
+                });
+                while let Some(token) = tokens.next() {
+                    if matches!(token, Token::Semicolon | Token::CloseGraphParenthesis) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(statements)
+    } else {
+        Err(errors)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::lexer::Token;
     use crate::lexer::Token::*;
-    use crate::parser::{parse_input, Expr, Statement, Term};
+    use crate::parser::{parse_input, parse_program, Expr, Statement, Term};
     use std::{println, vec};
 
     #[test]
@@ -194,6 +809,7 @@ mod tests {
             Identifier("x".to_string()),
             Token::Assignment,
             Token::Integer(10),
+            Token::Semicolon,
         ];
         let ret = parse_input(input).unwrap();
         assert_eq!(
@@ -209,6 +825,7 @@ mod tests {
             Identifier("x".to_string()),
             Token::Assignment,
             Token::Integer(10),
+            Token::Semicolon,
         ];
         let ret = parse_input(input).unwrap();
         assert_eq!(
@@ -221,6 +838,266 @@ mod tests {
         );
         println!("ret: {:?}", ret);
     }
+    fn parse_expression(tokens: Vec<Token>) -> Expr {
+        super::parse_expr(&mut tokens.into()).unwrap()
+    }
+
+    #[test]
+    fn test_error_position() {
+        let source = "let a := 1;\nlet b := 2;\nlet c := (a + b;\n";
+        let tokens = crate::lexer::parse_with_positions(source).unwrap();
+        let err = parse_program(tokens).unwrap_err().to_string();
+        assert!(err.ends_with("at line 3, column 16"), "{err}");
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let source = "let a := ;\nlet b := 2;\nprint (b;\nprint b;";
+        let tokens = crate::lexer::parse_with_positions(source).unwrap();
+        let errors = super::parse_program_recovering(tokens).unwrap_err();
+        let lines: Vec<_> = errors
+            .iter()
+            .map(|error| error.position.unwrap().line)
+            .collect();
+        assert_eq!(lines, vec![1, 3]);
+        // `parse_program` stops at the first one.
+        let tokens = crate::lexer::parse_with_positions(source).unwrap();
+        let first = parse_program(tokens).unwrap_err().to_string();
+        assert_eq!(first, errors[0].to_string());
+
+        let tokens = crate::lexer::parse_with_positions("let a := 1;\nprint a;").unwrap();
+        assert_eq!(super::parse_program_recovering(tokens).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_mismatched_brackets() {
+        let parse = |source| {
+            let tokens = crate::lexer::parse_with_positions(source).unwrap();
+            parse_program(tokens).unwrap_err().to_string()
+        };
+        assert_eq!(
+            parse("print s[0};"),
+            "mismatched bracket: expected ']' but found '}' (opened at line 1, column 8) at line 1, column 10"
+        );
+        assert_eq!(
+            parse("if true {\n  print 1;\n]"),
+            "mismatched bracket: expected '}' but found ']' (opened at line 1, column 9) at line 3, column 1"
+        );
+        assert!(
+            parse("print [1, 2};").starts_with("mismatched bracket: expected ']' but found '}'")
+        );
+        assert!(parse("print len(\"a\"];")
+            .starts_with("mismatched bracket: expected ')' but found ']'"));
+        assert!(
+            parse("print (1 + 2];").starts_with("mismatched bracket: expected ')' but found ']'")
+        );
+        // Without positions, there's no opening bracket to point at.
+        let tokens = vec![
+            Identifier("s".to_string()),
+            OpenSquareParenthesis,
+            Integer(0),
+            CloseGraphParenthesis,
+        ];
+        let err = super::parse_expr(&mut tokens.into()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "mismatched bracket: expected ']' but found '}'"
+        );
+    }
+
+    #[test]
+    fn test_unterminated_index() {
+        let tokens = vec![
+            Identifier("s".to_string()),
+            OpenSquareParenthesis,
+            Integer(0),
+        ];
+        let err = super::parse_expr(&mut tokens.into()).unwrap_err();
+        assert_eq!(err.to_string(), "expected ']', received: None");
+        let tokens = vec![
+            Identifier("s".to_string()),
+            OpenSquareParenthesis,
+            Integer(0),
+            CloseRoundParenthesis,
+        ];
+        assert!(super::parse_expr(&mut tokens.into()).is_err());
+    }
+
+    #[test]
+    fn test_array_literal() {
+        let expr = parse_expression(vec![
+            OpenSquareParenthesis,
+            Integer(1),
+            Comma,
+            Integer(2),
+            Addition,
+            Integer(3),
+            CloseSquareParenthesis,
+        ]);
+        let expected = Term::ArrayLiteral(vec![
+            Expr::TermWrapper(Term::Integer(1)),
+            Expr::Add(
+                Box::new(Expr::TermWrapper(Term::Integer(2))),
+                Box::new(Expr::TermWrapper(Term::Integer(3))),
+            ),
+        ]);
+        assert_eq!(expr, Expr::TermWrapper(expected));
+        let empty = parse_expression(vec![OpenSquareParenthesis, CloseSquareParenthesis]);
+        assert_eq!(empty, Expr::TermWrapper(Term::ArrayLiteral(vec![])));
+        let tokens = vec![OpenSquareParenthesis, Integer(1), Integer(2)];
+        assert!(super::parse_expr(&mut tokens.into()).is_err());
+    }
+
+    #[test]
+    fn test_bare_condition() {
+        let program = crate::lexer::parse("while flag { flag := false; }").unwrap();
+        let Statement::While(condition, None, _) = &parse_input(program).unwrap()[0] else {
+            panic!("expected a while loop");
+        };
+        assert_eq!(
+            **condition,
+            Expr::TermWrapper(Term::Variable("flag".to_string()))
+        );
+
+        let program = crate::lexer::parse("while flag other { }").unwrap();
+        let err = parse_input(program).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unexpected token Identifier(\"other\") after expression"
+        );
+    }
+
+    #[test]
+    fn test_postfix_chain() {
+        let tokens = crate::lexer::parse(r#"take("hello".drop(1), 3)[1]"#).unwrap();
+        let expr = parse_expression(tokens);
+        let string = |s: &str| Expr::TermWrapper(Term::String(s.into()));
+        let drop = Expr::TermWrapper(Term::Call(
+            "drop".to_string(),
+            vec![string("hello"), Expr::TermWrapper(Term::Integer(1))],
+        ));
+        let take = Expr::TermWrapper(Term::Call(
+            "take".to_string(),
+            vec![drop, Expr::TermWrapper(Term::Integer(3))],
+        ));
+        let expected = Expr::Index(
+            Box::new(take),
+            Box::new(Expr::TermWrapper(Term::Integer(1))),
+        );
+        assert_eq!(expr, expected);
+        let tokens = crate::lexer::parse("s.take").unwrap();
+        assert!(super::parse_expr(&mut tokens.into()).is_err());
+    }
+
+    #[test]
+    fn test_empty_statement() {
+        let program = crate::lexer::parse(";print 1;; while true { ; break; };").unwrap();
+        let program = parse_input(program).unwrap();
+        assert_eq!(program.len(), 5);
+        assert_eq!(program[0], Statement::Empty);
+        assert_eq!(program[2], Statement::Empty);
+        assert_eq!(program[4], Statement::Empty);
+    }
+
+    #[test]
+    fn test_negation() {
+        let int = |i| Box::new(Expr::TermWrapper(Term::Integer(i)));
+        let variable = Box::new(Expr::TermWrapper(Term::Variable("x".to_string())));
+        // -x + 2
+        let expr = parse_expression(vec![
+            Subtraction,
+            Identifier("x".to_string()),
+            Addition,
+            Integer(2),
+        ]);
+        assert_eq!(expr, Expr::Add(Box::new(Expr::Negate(variable)), int(2)));
+        // 0 - -5 - 1
+        let expr = parse_expression(vec![
+            Integer(0),
+            Subtraction,
+            Subtraction,
+            Integer(5),
+            Subtraction,
+            Integer(1),
+        ]);
+        let zero_minus_negated = Expr::Subtract(int(0), Box::new(Expr::Negate(int(5))));
+        assert_eq!(expr, Expr::Subtract(Box::new(zero_minus_negated), int(1)));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let int = |i| Box::new(Expr::TermWrapper(Term::Integer(i)));
+        // 2 + 3 * 4
+        let expr = parse_expression(vec![
+            Integer(2),
+            Addition,
+            Integer(3),
+            Multiplication,
+            Integer(4),
+        ]);
+        assert_eq!(
+            expr,
+            Expr::Add(int(2), Box::new(Expr::Multiply(int(3), int(4))))
+        );
+        // 1 + 2 + 3
+        let expr = parse_expression(vec![Integer(1), Addition, Integer(2), Addition, Integer(3)]);
+        assert_eq!(expr, Expr::Add(Box::new(Expr::Add(int(1), int(2))), int(3)));
+        // 1 < 2 == true
+        let expr = parse_expression(vec![Integer(1), LessThan, Integer(2), Equality, True]);
+        assert_eq!(
+            expr,
+            Expr::Equality(
+                Box::new(Expr::LessThan(int(1), int(2))),
+                Box::new(Expr::TermWrapper(Term::Boolean(true)))
+            )
+        );
+    }
+
+    #[test]
+    fn test_grouping() {
+        let int = |i| Box::new(Expr::TermWrapper(Term::Integer(i)));
+        // (1 + 2) * 3
+        let expr = parse_expression(vec![
+            OpenRoundParenthesis,
+            Integer(1),
+            Addition,
+            Integer(2),
+            CloseRoundParenthesis,
+            Multiplication,
+            Integer(3),
+        ]);
+        assert_eq!(
+            expr,
+            Expr::Multiply(Box::new(Expr::Add(int(1), int(2))), int(3))
+        );
+
+        let unclosed = vec![OpenRoundParenthesis, Integer(1), Addition, Integer(2)];
+        let err = super::parse_expr(&mut unclosed.into()).unwrap_err();
+        assert!(err.to_string().contains("Expected ')'"));
+    }
+
+    #[test]
+    fn test_call() {
+        let call = |args: Vec<Token>| {
+            let mut tokens = vec![Identifier("f".to_string()), OpenRoundParenthesis];
+            tokens.extend(args);
+            tokens.push(CloseRoundParenthesis);
+            parse_expression(tokens)
+        };
+        let int = |i| Expr::TermWrapper(Term::Integer(i));
+        assert_eq!(
+            call(vec![]),
+            Expr::TermWrapper(Term::Call("f".to_string(), vec![]))
+        );
+        assert_eq!(
+            call(vec![Integer(1), Comma, Integer(2), Addition, Integer(3)]),
+            Expr::TermWrapper(Term::Call(
+                "f".to_string(),
+                vec![int(1), Expr::Add(Box::new(int(2)), Box::new(int(3)))]
+            ))
+        );
+    }
+
     #[test]
     fn test_parser() {
         let input = vec![
@@ -246,7 +1123,7 @@ mod tests {
             Let,
             Identifier("quiz_input".to_string()),
             Assignment,
-            String("\n1abc2\n".to_string()),
+            String("\n1abc2\n".into()),
             Semicolon,
             Let,
             Identifier("sum".to_string()),
@@ -284,7 +1161,7 @@ mod tests {
             Identifier("index".to_string()),
             CloseSquareParenthesis,
             Disequality,
-            String("\\n".to_string()),
+            String("\\n".into()),
             OpenGraphParenthesis,
             If,
             Identifier("quiz_input".to_string()),
@@ -292,7 +1169,7 @@ mod tests {
             Identifier("index".to_string()),
             CloseSquareParenthesis,
             In,
-            String("0123456789".to_string()),
+            String("0123456789".into()),
             OpenGraphParenthesis,
             If,
             Identifier("is_first_digit_found".to_string()),
@@ -0,0 +1,110 @@
+use crate::parser::Statement;
+use crate::runtime::Interpreter;
+use crate::{lexer, parser};
+use anyhow::Result;
+use std::io::{self, Write};
+
+pub const DEFAULT_PROMPT: &str = "bina> ";
+
+/// Reads the session line by line with `read_line`, echoing the value of each expression to
+/// `output` with a `=> ` prefix. Errors are reported to `output` too, the session then goes on
+/// with the next line.
+///
+/// `read_line` appends a line to its argument like `BufRead::read_line`. It's called once per
+/// line rather than reading ahead, so that `input()` gets the lines after the one calling it.
+pub fn run(
+    interpreter: &mut Interpreter,
+    mut read_line: impl FnMut(&mut String) -> io::Result<usize>,
+    mut output: impl Write,
+    prompt: &str,
+) -> Result<()> {
+    interpreter.roll_back_failures();
+    loop {
+        write!(output, "{prompt}")?;
+        output.flush()?;
+        let mut line = String::new();
+        if read_line(&mut line)? == 0 {
+            break;
+        }
+        if let Err(e) = eval_line(interpreter, &line, &mut output) {
+            writeln!(output, "{e}")?;
+        }
+    }
+    writeln!(output)?;
+    Ok(())
+}
+
+/// Runs a single line. A line ending with `;` (or a block) is run silently; otherwise its last
+/// statement is taken as an expression whose value gets echoed.
+fn eval_line(interpreter: &mut Interpreter, line: &str, output: &mut impl Write) -> Result<()> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(());
+    }
+    let echo = !line.ends_with(';') && !line.ends_with('}');
+    let source = if echo {
+        format!("{line};")
+    } else {
+        line.to_string()
+    };
+    let promote_large_integers = interpreter.options().promote_large_integers;
+    let tokens = lexer::parse_with_options(&source, promote_large_integers)?;
+    let mut program = parser::parse_program(tokens)?;
+    let echoed = match program.last() {
+        Some(Statement::AtLine(_, statement)) if echo => match statement.as_ref() {
+            Statement::Expression(expr) => Some(expr.clone()),
+            _ => None,
+        },
+        _ => None,
+    };
+    if echoed.is_some() {
+        program.pop();
+    }
+    interpreter.run(program)?;
+    if let Some(expr) = echoed {
+        let value = interpreter.eval_expression(&expr)?;
+        writeln!(output, "=> {value}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::RunOptions;
+    use std::io::BufRead;
+
+    fn run_repl(input: &str) -> String {
+        let mut interpreter = Interpreter::with_output(
+            Box::new(std::io::sink()),
+            Box::new(std::io::sink()),
+            RunOptions::default(),
+        );
+        let mut output = Vec::new();
+        let mut input = input.as_bytes();
+        let read_line = |line: &mut String| input.read_line(line);
+        run(&mut interpreter, read_line, &mut output, "> ").unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_echo() {
+        assert_eq!(run_repl("1 + 1\n"), "> => 2\n> \n");
+        assert_eq!(run_repl("let x := 5;\n"), "> > \n");
+        assert_eq!(
+            run_repl("let x := 5;\nx * 2\nx * 3;\n"),
+            "> > => 10\n> > \n"
+        );
+    }
+
+    #[test]
+    fn test_errors_keep_the_session() {
+        let output = run_repl("let x := 5;\nlet y := (x;\nx := x + y;\nx + 1\n");
+        assert!(output.starts_with("> > Expected ')'"), "{output}");
+        assert!(
+            // Like every other line of the session, the failing one is line 1.
+            output.ends_with("> runtime error at line 1: variable \"y\" not found\n> => 6\n> \n"),
+            "{output}"
+        );
+    }
+}
@@ -1,184 +1,1436 @@
-use crate::parser::Expr::{Add, ContainedIn, DisEquality, Multiply, TermWrapper};
+use crate::builtins;
+use crate::parser::Expr::{Add, ContainedIn, DisEquality, Modulo, Multiply, TermWrapper};
 use crate::parser::{Expr, Statement, Term};
 use anyhow::{anyhow, bail, Context, Result};
 use log::debug;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
 
 #[derive(Clone, Debug, PartialEq)]
-enum Value {
+pub enum Value {
     Number(i64),
+    /// From a literal like `3.5`, or the result of `/` with true division on.
+    Float(f64),
     Boolean(bool),
-    String(String),
+    // Values are cloned all the time, `Rc` makes it cheap for strings and arrays.
+    String(Rc<str>),
+    Array(Rc<Vec<Value>>),
+    Map(Rc<HashMap<String, Value>>),
+    Null,
 }
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Null => "null",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{n}"),
+            // `{:?}` keeps the `.0` of whole numbers, to tell `2.0` and `2` apart.
+            Value::Float(x) => write!(f, "{x:?}"),
+            Value::Boolean(b) => write!(f, "{b}"),
+            Value::String(s) => write!(f, "{s}"),
+            Value::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    element.fmt_nested(f)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                // Sorted, so that the same map always prints the same.
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by_key(|(key, _)| *key);
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.into_iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{key:?}: ")?;
+                    value.fmt_nested(f)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Null => write!(f, "null"),
+        }
+    }
+}
+impl Value {
+    /// Formats a value inside an array or a map, quoting strings to tell `["1"]` and `[1]` apart.
+    fn fmt_nested(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{s:?}"),
+            value => write!(f, "{value}"),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Number(n)
+    }
+}
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s.into())
+    }
+}
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.into())
+    }
+}
+impl From<Vec<Value>> for Value {
+    fn from(elements: Vec<Value>) -> Self {
+        Value::Array(Rc::new(elements))
+    }
+}
+impl TryFrom<Value> for i64 {
+    type Error = anyhow::Error;
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Number(n) => Ok(n),
+            _ => bail!("Error: expected a number, found {value:?}"),
+        }
+    }
+}
+impl TryFrom<Value> for bool {
+    type Error = anyhow::Error;
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            _ => bail!("Error: expected a boolean, found {value:?}"),
+        }
+    }
+}
+impl TryFrom<Value> for String {
+    type Error = anyhow::Error;
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::String(s) => Ok(s.to_string()),
+            _ => bail!("Error: expected a string, found {value:?}"),
+        }
+    }
+}
+impl TryFrom<Value> for Vec<Value> {
+    type Error = anyhow::Error;
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::Array(elements) => Ok(Rc::unwrap_or_clone(elements)),
+            _ => bail!("Error: expected an array, found {value:?}"),
+        }
+    }
+}
+
 type Environment = HashMap<String, Value>;
 
-// todo: right now, all variables are basically global
-type EnvironmentStack = Vec<Environment>;
+/// The variables in scope. The global ones are shared until written to, so that function calls
+/// and the snapshots taken by `Interpreter::run` don't copy them.
+#[derive(Clone, Debug, Default)]
+struct EnvironmentStack {
+    globals: Rc<Environment>,
+    /// One `Environment` per nested scope, the innermost scope is the last one.
+    scopes: Vec<Environment>,
+}
+
+impl EnvironmentStack {
+    fn push(&mut self, scope: Environment) {
+        self.scopes.push(scope);
+    }
+    fn pop(&mut self) {
+        self.scopes.pop();
+    }
+    /// The scope `let` declares variables in.
+    fn innermost(&mut self) -> &mut Environment {
+        match self.scopes.last_mut() {
+            Some(scope) => scope,
+            None => Rc::make_mut(&mut self.globals),
+        }
+    }
+    /// Every scope, from the outermost one.
+    fn iter(&self) -> impl Iterator<Item = &Environment> {
+        std::iter::once(self.globals.as_ref()).chain(&self.scopes)
+    }
+}
+
+/// Looks `name` up, starting from the innermost scope.
+fn lookup<'a>(env: &'a EnvironmentStack, name: &str) -> Option<&'a Value> {
+    env.scopes
+        .iter()
+        .rev()
+        .find_map(|scope| scope.get(name))
+        .or_else(|| env.globals.get(name))
+}
+fn lookup_mut<'a>(env: &'a mut EnvironmentStack, name: &str) -> Option<&'a mut Value> {
+    if let Some(value) = env
+        .scopes
+        .iter_mut()
+        .rev()
+        .find_map(|scope| scope.get_mut(name))
+    {
+        return Some(value);
+    }
+    if !env.globals.contains_key(name) {
+        return None;
+    }
+    Rc::make_mut(&mut env.globals).get_mut(name)
+}
+
+/// The error for a variable that's not in scope, suggesting the closest name that is,
+/// to point out typos.
+fn variable_not_found(env: &EnvironmentStack, name: &str) -> anyhow::Error {
+    // Short names are a few edits away from any other short name, they'd make noisy suggestions.
+    let length = name.chars().count();
+    let closest = env
+        .iter()
+        .flat_map(|scope| scope.keys())
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance < length && *distance <= length / 3 + 1)
+        .min();
+    match closest {
+        Some((_, candidate)) => anyhow!("variable {name:?} not found, did you mean {candidate:?}?"),
+        None => anyhow!("variable {name:?} not found"),
+    }
+}
+
+/// The Levenshtein distance between `a` and `b`: how many characters have to be inserted,
+/// removed or replaced to turn one into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // The distances from the prefix of `a` seen so far to every prefix of `b`.
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let replaced = diagonal + usize::from(a_char != *b_char);
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    /// Abort the program once it tries to print more than this many bytes to stdout. What goes
+    /// to stderr doesn't count, so that diagnostics and `--profile` don't change the outcome.
+    pub max_output_bytes: Option<usize>,
+    /// Count the calls to each function, reporting them on stderr once the program is done.
+    pub profile: bool,
+    /// Don't coerce strings to numbers in arithmetic, `"5" + 1` is an error instead.
+    pub strict_types: bool,
+    /// `/` always gives a float, `7 / 2 == 3.5`, rather than truncating to an integer.
+    pub true_division: bool,
+    /// Report a failing top level statement on stderr and go on with the next one, rather than
+    /// stopping the program. It still fails at the end.
+    pub continue_on_error: bool,
+    /// Integer literals too large for a number are floats rather than an error. Applied when
+    /// lexing the program, it's here to be set along with the others.
+    pub promote_large_integers: bool,
+    /// The command line arguments for the program, returned by `args()`.
+    pub script_args: Vec<String>,
+}
+
+/// Destination of the program's output, keeping track of how much it has written to stdout so far.
+struct OutputSink {
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+    written_bytes: usize,
+    max_bytes: Option<usize>,
+}
+
+impl OutputSink {
+    fn new(stdout: Box<dyn Write>, stderr: Box<dyn Write>, max_bytes: Option<usize>) -> Self {
+        Self {
+            stdout,
+            stderr,
+            written_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn count_bytes(&mut self, bytes: usize) -> Result<()> {
+        let written_bytes = self.written_bytes + bytes;
+        if self.max_bytes.is_some_and(|max| written_bytes > max) {
+            bail!("Error: output limit exceeded");
+        }
+        self.written_bytes = written_bytes;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        self.count_bytes(line.len() + 1)?;
+        writeln!(self.stdout, "{line}").context("Error writing output")?;
+        Ok(())
+    }
+
+    /// Writes `text` as is, flushing it so that it shows up before the program reads its input.
+    fn write(&mut self, text: &str) -> Result<()> {
+        self.count_bytes(text.len())?;
+        write!(self.stdout, "{text}").context("Error writing output")?;
+        self.stdout.flush().context("Error writing output")?;
+        Ok(())
+    }
+
+    fn write_error_line(&mut self, line: &str) -> Result<()> {
+        writeln!(self.stderr, "{line}").context("Error writing output")?;
+        Ok(())
+    }
+}
+
+/// A `break` or `continue` on its way to the closest loop, or a `return` on its way to the
+/// function call.
+#[derive(Debug, Clone, PartialEq)]
+enum Jump {
+    Break,
+    Continue,
+    Return(Value),
+}
+
+/// A function declared with `fn`.
+struct Function {
+    parameters: Vec<String>,
+    body: Statement,
+}
+
+/// How deeply function calls can nest.
+const MAX_CALL_DEPTH: usize = 1000;
+
+/// Everything besides the variables that the evaluation needs to carry along.
+struct State {
+    out: OutputSink,
+    options: RunOptions,
+    /// How many times each function was called, if profiling.
+    calls: Option<HashMap<String, usize>>,
+    /// Set by `break`, `continue` and `return`: the statements left in the enclosing blocks are
+    /// skipped until a loop or a function call takes it.
+    jump: Option<Jump>,
+    functions: HashMap<String, Rc<Function>>,
+    /// The line of the innermost statement being run, if known. Left as is when the statement
+    /// fails, to tell where the error is.
+    line: Option<usize>,
+    /// How many function calls are in progress, to fail cleanly on runaway recursion rather than
+    /// overflowing the stack.
+    depth: usize,
+}
+
+impl State {
+    fn new(out: OutputSink, options: &RunOptions) -> Self {
+        State {
+            out,
+            options: options.clone(),
+            calls: options.profile.then(HashMap::new),
+            jump: None,
+            functions: HashMap::new(),
+            line: None,
+            depth: 0,
+        }
+    }
+
+    /// Fails if a `break` or `continue` got past every loop, or a `return` past every call.
+    fn check_no_jump(&mut self) -> Result<()> {
+        match self.jump.take() {
+            Some(Jump::Break) => bail!("Error: 'break' outside of a loop"),
+            Some(Jump::Continue) => bail!("Error: 'continue' outside of a loop"),
+            Some(Jump::Return(_)) => bail!("Error: 'return' outside of a function"),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the call counts, most called function first.
+    fn report_profile(&mut self) -> Result<()> {
+        let Some(calls) = &self.calls else {
+            return Ok(());
+        };
+        let mut calls: Vec<_> = calls
+            .iter()
+            .map(|(name, count)| (name.clone(), *count))
+            .collect();
+        calls.sort_by(|(l_name, l_count), (r_name, r_count)| {
+            r_count.cmp(l_count).then_with(|| l_name.cmp(r_name))
+        });
+        for (name, count) in calls {
+            self.out
+                .write_error_line(&format!("{name}: {count} calls"))?;
+        }
+        Ok(())
+    }
+}
 
 fn evaluate_assignment(
-    mut env: Environment,
-    variable_name: String,
-    expr: Box<Expr>,
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    variable_name: &str,
+    expr: &Expr,
     is_let: bool,
-) -> Result<Environment> {
-    let value = eval_expr(&env, expr)?;
-    env.insert(variable_name, value);
-
-    Ok(env)
+) -> Result<()> {
+    let value = eval_expr(state, env, expr)?;
+    // `let` declares in the current scope, shadowing outer variables, while a plain assignment
+    // updates the closest existing variable.
+    if is_let {
+        env.innermost().insert(variable_name.to_string(), value);
+    } else {
+        let binding = lookup_mut(env, variable_name).with_context(|| {
+            format!("Error: assignment to undeclared variable {variable_name:?}, use 'let' to declare it")
+        })?;
+        *binding = value;
+    }
+    Ok(())
+}
+/// `variable[index] := expr`, replacing an element of an array or a character of a string.
+fn evaluate_indexed_assignment(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    variable_name: &str,
+    index: &Expr,
+    expr: &Expr,
+) -> Result<()> {
+    let index = eval_expr(state, env, index)?;
+    let value = eval_expr(state, env, expr)?;
+    let binding = lookup_mut(env, variable_name)
+        .with_context(|| format!("Error: assignment to undeclared variable {variable_name:?}"))?;
+    if let Value::Map(map) = binding {
+        let Value::String(key) = index else {
+            bail!("Error: map keys must be strings, found {index:?}");
+        };
+        // Copies the entries first if another value shares them.
+        Rc::make_mut(map).insert(key.to_string(), value);
+        return Ok(());
+    }
+    let Value::Number(n) = index else {
+        bail!("Error: index {index:?} is not a number");
+    };
+    let out_of_bounds = || format!("Error: index {n} out of bounds of {variable_name:?}");
+    let n = usize::try_from(n).ok();
+    match (binding, value) {
+        (Value::Array(elements), value) => {
+            // Copies the elements first if another value shares them.
+            let element = n
+                .and_then(|n| Rc::make_mut(elements).get_mut(n))
+                .with_context(out_of_bounds)?;
+            *element = value;
+        }
+        (Value::String(s), Value::String(replacement)) if replacement.chars().count() == 1 => {
+            let (offset, ch) = n
+                .and_then(|n| s.char_indices().nth(n))
+                .with_context(out_of_bounds)?;
+            let mut replaced = s.to_string();
+            replaced.replace_range(offset..offset + ch.len_utf8(), &replacement);
+            *s = replaced.into();
+        }
+        (Value::String(_), value) => {
+            bail!(
+                "Error: only a single character can be assigned to a string index, found {value:?}"
+            )
+        }
+        (binding, _) => bail!(
+            "Error: {variable_name:?} is a {}, it can't be indexed",
+            binding.type_name()
+        ),
+    }
+    Ok(())
 }
-fn eval_term(env: &Environment, term: Box<Term>) -> Result<Value> {
-    Ok(match term.as_ref() {
+fn eval_term(state: &mut State, env: &mut EnvironmentStack, term: &Term) -> Result<Value> {
+    Ok(match term {
         Term::String(s) => Value::String(s.clone()),
         Term::Integer(n) => Value::Number(*n),
+        Term::Float(x) => Value::Float(*x),
         Term::Boolean(b) => Value::Boolean(*b),
+        Term::Null => Value::Null,
         Term::Variable(s) => {
             debug!("eval_term: variable {s:?} found in env {:?}", env);
-            let value = env.get(s).context("variable not found")?;
+            let value = lookup(env, s).ok_or_else(|| variable_not_found(env, s))?;
             value.clone()
         }
         Term::VariableIndexed(s, expr) => {
-            let base_array = env.get(s).context("variable not found")?;
-            let index = eval_expr(env, expr.clone())?;
-            if let (Value::Number(n), Value::String(s)) = (index.clone(), base_array.clone()) {
-                let ret = s
-                    .chars()
-                    .nth(n as usize)
-                    .context("variableIndexed: index out of bounds")?;
-                Value::String(ret.to_string())
+            let base_array = lookup(env, s).ok_or_else(|| variable_not_found(env, s))?;
+            eval_index(state, env, &base_array.clone(), expr)?
+        }
+        Term::ArrayLiteral(elements) => Value::from(
+            elements
+                .iter()
+                .map(|element| eval_expr(state, env, element))
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        Term::MapLiteral(entries) => {
+            let mut map = HashMap::new();
+            for (key, value) in entries {
+                let key = match eval_expr(state, env, key)? {
+                    Value::String(key) => key.to_string(),
+                    key => bail!("Error: map keys must be strings, found {key:?}"),
+                };
+                map.insert(key, eval_expr(state, env, value)?);
+            }
+            Value::Map(Rc::new(map))
+        }
+        Term::Block(statements) => eval_block_value(state, env, statements)?,
+        Term::Call(name, arguments) => {
+            let arguments = arguments
+                .iter()
+                .map(|argument| eval_expr(state, env, argument))
+                .collect::<Result<Vec<_>>>()?;
+            if let Some(calls) = &mut state.calls {
+                *calls.entry(name.clone()).or_default() += 1;
+            }
+            if let Some(function) = state.functions.get(name).cloned() {
+                call_function(state, env, name, &function, arguments)?
+            } else if name == "config" {
+                eval_config(state, arguments)?
+            } else if name == "args" {
+                eval_args(state, arguments)?
+            } else if name == "tee" {
+                eval_tee(state, arguments)?
+            } else if name == "times" {
+                eval_times(state, env, arguments)?
+            } else if name == "reduce" {
+                eval_reduce(state, env, arguments)?
             } else {
-                bail!("Error: base_array : {base_array:?} is not a string or index : {index:?} is not a number")
+                builtins::call(name, arguments)?
             }
         }
     })
 }
-fn eval_expr(env: &Environment, expr: Box<Expr>) -> Result<Value> {
-    match expr.as_ref().clone() {
+/// `times(n, name)` calls the function `name` `n` times, collecting the results in an array.
+/// If the function takes an argument, it's the index of the call.
+fn eval_times(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    arguments: Vec<Value>,
+) -> Result<Value> {
+    let [Value::Number(count), Value::String(name)] = arguments.as_slice() else {
+        bail!("Error: times expects a count and the name of a function");
+    };
+    let function = state
+        .functions
+        .get(&**name)
+        .cloned()
+        .with_context(|| format!("Error: unknown function {name:?}"))?;
+    let takes_index = match function.parameters.len() {
+        0 => false,
+        1 => true,
+        n => bail!("Error: times expects a function taking 0 or 1 arguments, {name} takes {n}"),
+    };
+    (0..*count)
+        .map(|i| {
+            let arguments = if takes_index {
+                vec![Value::Number(i)]
+            } else {
+                vec![]
+            };
+            call_function(state, env, name, &function, arguments)
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Value::from)
+}
+/// `reduce(array, name, init)` folds the two arguments function `name` over `array`:
+/// `reduce([1, 2], "add", 0)` is `add(add(0, 1), 2)`.
+fn eval_reduce(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    arguments: Vec<Value>,
+) -> Result<Value> {
+    let Ok([Value::Array(elements), Value::String(name), init]) = <[Value; 3]>::try_from(arguments)
+    else {
+        bail!("Error: reduce expects an array, the name of a function and an initial value");
+    };
+    let function = state
+        .functions
+        .get(&*name)
+        .cloned()
+        .with_context(|| format!("Error: unknown function {name:?}"))?;
+    elements.iter().try_fold(init, |accumulator, element| {
+        call_function(
+            state,
+            env,
+            &name,
+            &function,
+            vec![accumulator, element.clone()],
+        )
+    })
+}
+/// Runs `statements` in a scope of their own and returns the value of the last one, which has to
+/// be an expression. As in any block, assignments to the outer variables stick.
+fn eval_block_value(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    statements: &[Statement],
+) -> Result<Value> {
+    let (line, last) = match statements.last() {
+        Some(Statement::AtLine(line, statement)) => (Some(*line), statement.as_ref()),
+        Some(statement) => (None, statement),
+        None => bail!("Error: a block used as a value must end with an expression"),
+    };
+    let Statement::Expression(expr) = last else {
+        bail!("Error: a block used as a value must end with an expression");
+    };
+    env.push(Environment::new());
+    let value = eval_block_statements(state, env, statements, line, expr);
+    env.pop();
+    value
+}
+fn eval_block_statements(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    statements: &[Statement],
+    line: Option<usize>,
+    expr: &Expr,
+) -> Result<Value> {
+    for statement in &statements[..statements.len() - 1] {
+        eval(state, env, statement)?;
+        // A `break`, `continue` or `return` leaves the block before it has a value.
+        if state.jump.is_some() {
+            return Ok(Value::Null);
+        }
+    }
+    let outer = match line {
+        Some(line) => state.line.replace(line),
+        None => state.line,
+    };
+    let value = eval_expr(state, env, expr)?;
+    state.line = outer;
+    Ok(value)
+}
+/// Runs `function` in a new scope holding its arguments. The body sees the global variables, but
+/// not the local ones of the caller.
+fn call_function(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    name: &str,
+    function: &Function,
+    arguments: Vec<Value>,
+) -> Result<Value> {
+    if arguments.len() != function.parameters.len() {
+        bail!(
+            "Error: {name} expects {} arguments, received {}",
+            function.parameters.len(),
+            arguments.len()
+        );
+    }
+    if state.depth == MAX_CALL_DEPTH {
+        bail!("Error: maximum call depth of {MAX_CALL_DEPTH} exceeded in {name}");
+    }
+    let frame = function.parameters.iter().cloned().zip(arguments).collect();
+    // The caller's scopes are put back once the call is done, even if it fails.
+    let caller_scopes = std::mem::replace(&mut env.scopes, vec![frame]);
+    state.depth += 1;
+    let result = eval(state, env, &function.body);
+    state.depth -= 1;
+    env.scopes = caller_scopes;
+    result?;
+    match state.jump.take() {
+        Some(Jump::Return(value)) => Ok(value),
+        None => Ok(Value::Null),
+        Some(Jump::Break) => bail!("Error: 'break' outside of a loop"),
+        Some(Jump::Continue) => bail!("Error: 'continue' outside of a loop"),
+    }
+}
+/// `config()` is a map from the name of each run option to its value, so that scripts can check
+/// how they're run, e.g. `config()["strict_types"]`. The arguments are left to `args()`.
+fn eval_config(state: &State, arguments: Vec<Value>) -> Result<Value> {
+    if !arguments.is_empty() {
+        bail!(
+            "Error: config expects 0 arguments, received {}",
+            arguments.len()
+        );
+    }
+    let options = &state.options;
+    let max_output_bytes = match options.max_output_bytes {
+        Some(max) => Value::Number(max as i64),
+        None => Value::Null,
+    };
+    let config = HashMap::from([
+        ("strict_types", Value::Boolean(options.strict_types)),
+        ("profile", Value::Boolean(options.profile)),
+        ("true_division", Value::Boolean(options.true_division)),
+        (
+            "continue_on_error",
+            Value::Boolean(options.continue_on_error),
+        ),
+        (
+            "promote_large_integers",
+            Value::Boolean(options.promote_large_integers),
+        ),
+        ("max_output_bytes", max_output_bytes),
+    ]);
+    let config = config
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    Ok(Value::Map(Rc::new(config)))
+}
+/// `args()` is the array of the command line arguments given to the program.
+fn eval_args(state: &State, arguments: Vec<Value>) -> Result<Value> {
+    if !arguments.is_empty() {
+        bail!(
+            "Error: args expects 0 arguments, received {}",
+            arguments.len()
+        );
+    }
+    Ok(Value::from(
+        state
+            .options
+            .script_args
+            .iter()
+            .map(|arg| Value::from(arg.as_str()))
+            .collect::<Vec<_>>(),
+    ))
+}
+/// `tee(value, label)` writes `label: value` to stderr and returns `value`, to look at
+/// intermediate results without rewriting the expression.
+fn eval_tee(state: &mut State, arguments: Vec<Value>) -> Result<Value> {
+    let Ok([value, Value::String(label)]) = <[Value; 2]>::try_from(arguments) else {
+        bail!("Error: tee expects a value and a label");
+    };
+    state.out.write_error_line(&format!("{label}: {value}"))?;
+    Ok(value)
+}
+/// `base[index]`, or the slice `base[start..end]` when `index` is a range.
+fn eval_index(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    base: &Value,
+    index: &Expr,
+) -> Result<Value> {
+    let Expr::Range(start, end) = index else {
+        let index = eval_expr(state, env, index)?;
+        return index_value(base, &index);
+    };
+    let (start, end) = match (eval_expr(state, env, start)?, eval_expr(state, env, end)?) {
+        (Value::Number(start), Value::Number(end)) => (start, end),
+        (start, end) => bail!("Error: slice expects numbers, found {start:?}..{end:?}"),
+    };
+    let length = match base {
+        Value::String(s) => s.chars().count(),
+        Value::Array(elements) => elements.len(),
+        base => bail!("Error: a {} can't be sliced", base.type_name()),
+    };
+    if start > end {
+        bail!("Error: slice {start}..{end} starts after its end");
+    }
+    let (Ok(from), Ok(to)) = (usize::try_from(start), usize::try_from(end)) else {
+        bail!("Error: slice {start}..{end} out of bounds, the length is {length}");
+    };
+    if to > length {
+        bail!("Error: slice {start}..{end} out of bounds, the length is {length}");
+    }
+    Ok(match base {
+        Value::String(s) => Value::from(s.chars().skip(from).take(to - from).collect::<String>()),
+        Value::Array(elements) => Value::from(elements[from..to].to_vec()),
+        _ => unreachable!("checked above"),
+    })
+}
+/// The element of a string or an array at `index`.
+fn index_value(base_array: &Value, index: &Value) -> Result<Value> {
+    let element = match (index, base_array) {
+        (Value::String(key), Value::Map(map)) => {
+            return map
+                .get(&**key)
+                .cloned()
+                .with_context(|| format!("Error: key {key:?} not found in the map"));
+        }
+        (Value::Number(n), Value::String(s)) => usize::try_from(*n)
+            .ok()
+            .and_then(|n| s.chars().nth(n))
+            .map(|ch| Value::from(ch.to_string())),
+        (Value::Number(n), Value::Array(elements)) => usize::try_from(*n)
+            .ok()
+            .and_then(|n| elements.get(n))
+            .cloned(),
+        _ => bail!("Error: base_array : {base_array:?} is not a string or an array or index : {index:?} is not a number"),
+    };
+    element.with_context(|| format!("variableIndexed: index {index} out of bounds"))
+}
+/// Strings mixed with numbers in arithmetic are parsed as numbers, e.g. `"5" + 1 == 6`,
+/// unless strict types are on.
+fn coerce_to_number(state: &State, s: &str) -> Result<i64> {
+    if state.options.strict_types {
+        bail!("Error: cannot use {s:?} as a number, strict types are on");
+    }
+    s.parse::<i64>()
+        .with_context(|| format!("Error: cannot use {s:?} as a number"))
+}
+fn repeat_string(s: &str, count: i64) -> Result<Value> {
+    let Ok(count) = usize::try_from(count) else {
+        bail!("Error: cannot repeat a string a negative number of times ({count})");
+    };
+    if s.is_empty() {
+        return Ok(Value::from(""));
+    }
+    // Fails cleanly rather than aborting when the string can't fit in memory.
+    let mut repeated = String::new();
+    s.len()
+        .checked_mul(count)
+        .and_then(|len| repeated.try_reserve_exact(len).ok())
+        .context("Error: the repeated string would be too long")?;
+    for _ in 0..count {
+        repeated.push_str(s);
+    }
+    Ok(Value::from(repeated))
+}
+/// Wraps the result of a `checked_*` integer operation, which is `None` on overflow.
+fn checked_number(result: Option<i64>) -> Result<Value> {
+    result
+        .map(Value::Number)
+        .context("Error: arithmetic overflow")
+}
+/// The operands of an arithmetic operator as floats, if one is a float and the other a float or a
+/// number: the result is then a float too.
+fn float_operands(left: &Value, right: &Value) -> Option<(f64, f64)> {
+    match (left, right) {
+        (Value::Float(l), Value::Float(r)) => Some((*l, *r)),
+        (Value::Float(l), Value::Number(r)) => Some((*l, *r as f64)),
+        (Value::Number(l), Value::Float(r)) => Some((*l as f64, *r)),
+        _ => None,
+    }
+}
+/// `left == right`, where a number equals the float with the same value, `2 == 2.0`, also inside
+/// arrays and maps.
+fn values_equal(left: &Value, right: &Value) -> bool {
+    if let Some((l, r)) = float_operands(left, right) {
+        return l == r;
+    }
+    match (left, right) {
+        (Value::Array(l), Value::Array(r)) => {
+            l.len() == r.len() && l.iter().zip(r.iter()).all(|(l, r)| values_equal(l, r))
+        }
+        (Value::Map(l), Value::Map(r)) => {
+            l.len() == r.len()
+                && l.iter()
+                    .all(|(key, l)| r.get(key).is_some_and(|r| values_equal(l, r)))
+        }
+        (left, right) => left == right,
+    }
+}
+/// Ordering operators never coerce strings: both sides must be of the same, comparable, type,
+/// except that numbers and floats can be compared. Strings are compared lexicographically,
+/// by code point.
+fn compare_values(
+    state: &mut State,
+    operator: &str,
+    env: &mut EnvironmentStack,
+    left: &Expr,
+    right: &Expr,
+) -> Result<Ordering> {
+    let left = eval_expr(state, env, left)?;
+    let right = eval_expr(state, env, right)?;
+    if let Some((l, r)) = float_operands(&left, &right) {
+        return l.partial_cmp(&r).context("Error: NaN can't be compared");
+    }
+    match (&left, &right) {
+        (Value::Number(l), Value::Number(r)) => Ok(l.cmp(r)),
+        (Value::String(l), Value::String(r)) => Ok(l.cmp(r)),
+        _ => bail!(
+            "Error: {operator} between {} and {} is not supported",
+            left.type_name(),
+            right.type_name()
+        ),
+    }
+}
+fn eval_expr(state: &mut State, env: &mut EnvironmentStack, expr: &Expr) -> Result<Value> {
+    match expr {
         Add(left, right) => {
-            let left = eval_term(env, left)?;
-            let right = eval_term(env, right)?;
-            match (left, right) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
-                (Value::String(l), Value::Number(r)) => {
-                    Ok(Value::Number(l.parse::<i64>().unwrap() + r))
-                }
-                (Value::Number(l), Value::String(r)) => {
-                    Ok(Value::Number(l + r.parse::<i64>().unwrap()))
-                }
+            let left = eval_expr(state, env, left)?;
+            let right = eval_expr(state, env, right)?;
+            if let Some((l, r)) = float_operands(&left, &right) {
+                return Ok(Value::Float(l + r));
+            }
+            let (l, r) = match (left, right) {
+                (Value::Number(l), Value::Number(r)) => (l, r),
+                (Value::String(l), Value::String(r)) => return Ok(Value::from(format!("{l}{r}"))),
+                (Value::String(l), Value::Number(r)) => (coerce_to_number(state, &l)?, r),
+                (Value::Number(l), Value::String(r)) => (l, coerce_to_number(state, &r)?),
                 _ => bail!("Error: Addition of non-numbers"),
+            };
+            checked_number(l.checked_add(r))
+        }
+        Expr::Subtract(left, right) => {
+            let left = eval_expr(state, env, left)?;
+            let right = eval_expr(state, env, right)?;
+            if let Some((l, r)) = float_operands(&left, &right) {
+                return Ok(Value::Float(l - r));
             }
+            let (l, r) = match (left, right) {
+                (Value::Number(l), Value::Number(r)) => (l, r),
+                (Value::String(l), Value::Number(r)) => (coerce_to_number(state, &l)?, r),
+                (Value::Number(l), Value::String(r)) => (l, coerce_to_number(state, &r)?),
+                _ => bail!("Error: Subtraction of non-numbers"),
+            };
+            checked_number(l.checked_sub(r))
         }
         Multiply(left, right) => {
-            let left = eval_term(env, left)?;
-            let right = eval_term(env, right)?;
-            match (left, right) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l * r)),
-                (Value::String(l), Value::Number(r)) => {
-                    Ok(Value::Number(l.parse::<i64>().unwrap() * r))
-                }
-                (Value::Number(l), Value::String(r)) => {
-                    Ok(Value::Number(l * r.parse::<i64>().unwrap()))
-                }
+            let left = eval_expr(state, env, left)?;
+            let right = eval_expr(state, env, right)?;
+            if let Some((l, r)) = float_operands(&left, &right) {
+                return Ok(Value::Float(l * r));
+            }
+            let (l, r) = match (left, right) {
+                (Value::Number(l), Value::Number(r)) => (l, r),
+                // Repetition, `"ab" * 3 == "ababab"`. A negative count is an error.
+                (Value::String(l), Value::Number(r)) => return repeat_string(&l, r),
+                (Value::Number(l), Value::String(r)) => (l, coerce_to_number(state, &r)?),
                 _ => bail!("Error: Multiplication of non-numbers"),
+            };
+            checked_number(l.checked_mul(r))
+        }
+        Expr::Divide(left, right) => {
+            let left = eval_expr(state, env, left)?;
+            let right = eval_expr(state, env, right)?;
+            if let Some((l, r)) = float_operands(&left, &right) {
+                if r == 0.0 {
+                    bail!("Error: Division by zero");
+                }
+                return Ok(Value::Float(l / r));
+            }
+            let (l, r) = match (left, right) {
+                (Value::Number(l), Value::Number(r)) => (l, r),
+                (Value::String(l), Value::Number(r)) => (coerce_to_number(state, &l)?, r),
+                (Value::Number(l), Value::String(r)) => (l, coerce_to_number(state, &r)?),
+                _ => bail!("Error: Division of non-numbers"),
+            };
+            if r == 0 {
+                bail!("Error: Division by zero");
+            }
+            if state.options.true_division {
+                Ok(Value::Float(l as f64 / r as f64))
+            } else {
+                // Truncates towards zero, like Rust's `/`.
+                checked_number(l.checked_div(r))
             }
         }
-        Expr::Equality(left, right) => {
-            let left = eval_term(env, left)?;
-            let right = eval_term(env, right)?;
+        Modulo(left, right) => {
+            let left = eval_expr(state, env, left)?;
+            let right = eval_expr(state, env, right)?;
+            if let Some((l, r)) = float_operands(&left, &right) {
+                if r == 0.0 {
+                    bail!("Error: Modulo by zero");
+                }
+                return Ok(Value::Float(l % r));
+            }
             match (left, right) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Boolean(l == r)),
-                (Value::Boolean(l), Value::Boolean(r)) => Ok(Value::Boolean(l == r)),
-                _ => bail!("Error: DisEquality of non-numbers"),
+                (Value::Number(_), Value::Number(0)) => bail!("Error: Modulo by zero"),
+                // Same semantics as Rust's `%`: the result takes the sign of the dividend,
+                // so `-10 % 3 == -1`.
+                (Value::Number(l), Value::Number(r)) => checked_number(l.checked_rem(r)),
+                _ => bail!("Error: Modulo of non-numbers"),
             }
         }
-        Expr::LessThan(left, right) => {
-            let left = eval_term(env, left)?;
-            let right = eval_term(env, right)?;
+        Expr::LogicalOr(left, right) => {
+            // Short-circuit: the right side is only evaluated when the left one is false.
+            match eval_expr(state, env, left)? {
+                Value::Boolean(true) => Ok(Value::Boolean(true)),
+                Value::Boolean(false) => match eval_expr(state, env, right)? {
+                    Value::Boolean(r) => Ok(Value::Boolean(r)),
+                    right => bail!("Error: LogicalOr of non-booleans: {right:?}"),
+                },
+                left => bail!("Error: LogicalOr of non-booleans: {left:?}"),
+            }
+        }
+        Expr::LogicalAnd(left, right) => {
+            // Short-circuit: the right side is only evaluated when the left one is true.
+            match eval_expr(state, env, left)? {
+                Value::Boolean(false) => Ok(Value::Boolean(false)),
+                Value::Boolean(true) => match eval_expr(state, env, right)? {
+                    Value::Boolean(r) => Ok(Value::Boolean(r)),
+                    right => bail!("Error: LogicalAnd of non-booleans: {right:?}"),
+                },
+                left => bail!("Error: LogicalAnd of non-booleans: {left:?}"),
+            }
+        }
+        // Unlike `&&` and `||`, both sides are always evaluated.
+        Expr::BitAnd(left, right) | Expr::BitOr(left, right) => {
+            let is_and = matches!(expr, Expr::BitAnd(_, _));
+            let left = eval_expr(state, env, left)?;
+            let right = eval_expr(state, env, right)?;
             match (left, right) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Boolean(l < r)),
-                _ => bail!("Error: DisEquality of non-numbers"),
+                (Value::Number(l), Value::Number(r)) => {
+                    Ok(Value::Number(if is_and { l & r } else { l | r }))
+                }
+                (Value::Boolean(l), Value::Boolean(r)) => {
+                    Ok(Value::Boolean(if is_and { l & r } else { l | r }))
+                }
+                (left, right) => bail!(
+                    "Error: bitwise {} between {} and {} is not supported",
+                    if is_and { "and" } else { "or" },
+                    left.type_name(),
+                    right.type_name()
+                ),
             }
         }
+        // Short-circuit: the right side is only evaluated when the left one is null.
+        Expr::NullCoalesce(left, right) => match eval_expr(state, env, left)? {
+            Value::Null => eval_expr(state, env, right),
+            left => Ok(left),
+        },
+        // Any two values can be checked for equality: values of different types are never equal,
+        // except for numbers and floats.
+        Expr::Equality(left, right) => {
+            let left = eval_expr(state, env, left)?;
+            let right = eval_expr(state, env, right)?;
+            Ok(Value::Boolean(values_equal(&left, &right)))
+        }
         DisEquality(left, right) => {
-            let left = eval_term(env, left)?;
-            let right = eval_term(env, right)?;
-            match (left.clone(), right.clone()) {
-                (Value::Number(l), Value::Number(r)) => Ok(Value::Boolean(l != r)),
-                (Value::Boolean(l), Value::Boolean(r)) => Ok(Value::Boolean(l != r)),
-                (Value::String(l), Value::String(r)) => Ok(Value::Boolean(l != r)),
-                _ => bail!("Error: DisEquality not implemented for: {left:?},{right:?}"),
-            }
+            let left = eval_expr(state, env, left)?;
+            let right = eval_expr(state, env, right)?;
+            Ok(Value::Boolean(!values_equal(&left, &right)))
+        }
+        Expr::LessThan(left, right) => {
+            let ordering = compare_values(state, "LessThan", env, left, right)?;
+            Ok(Value::Boolean(ordering.is_lt()))
+        }
+        Expr::LessThanOrEqual(left, right) => {
+            let ordering = compare_values(state, "LessThanOrEqual", env, left, right)?;
+            Ok(Value::Boolean(ordering.is_le()))
+        }
+        Expr::GreaterThan(left, right) => {
+            let ordering = compare_values(state, "GreaterThan", env, left, right)?;
+            Ok(Value::Boolean(ordering.is_gt()))
+        }
+        Expr::GreaterThanOrEqual(left, right) => {
+            let ordering = compare_values(state, "GreaterThanOrEqual", env, left, right)?;
+            Ok(Value::Boolean(ordering.is_ge()))
         }
         ContainedIn(left, right) => {
-            let left = eval_term(env, left)?;
-            let right = eval_term(env, right)?;
+            if let Expr::Range(start, end) = right.as_ref() {
+                return eval_in_range(state, env, left, start, end);
+            }
+            let left = eval_expr(state, env, left)?;
+            let right = eval_expr(state, env, right)?;
             match (left, right) {
-                (Value::String(l), Value::String(r)) => Ok(Value::Boolean(r.contains(&l))),
+                (Value::String(l), Value::String(r)) => Ok(Value::Boolean(r.contains(&*l))),
                 _ => bail!("Error: ContainedIn of non-strings"),
             }
         }
-        TermWrapper(term) => eval_term(env, Box::new(term)),
-        expr => bail!("eval_expr: unimplemented {expr:?}"),
+        Expr::Index(base, index) => {
+            let base = eval_expr(state, env, base)?;
+            eval_index(state, env, &base, index)
+        }
+        Expr::Not(operand) => match eval_expr(state, env, operand)? {
+            Value::Boolean(b) => Ok(Value::Boolean(!b)),
+            value => bail!("Error: Not of non-boolean: {value:?}"),
+        },
+        Expr::Negate(operand) => match eval_expr(state, env, operand)? {
+            Value::Number(n) => checked_number(n.checked_neg()),
+            Value::Float(x) => Ok(Value::Float(-x)),
+            value => bail!("Error: Negation of non-number: {value:?}"),
+        },
+        Expr::Range(_, _) => bail!("Error: a range can only be used on the right of 'in'"),
+        TermWrapper(term) => eval_term(state, env, term),
+    }
+}
+/// `x in start..end`, checked without materializing the range.
+fn eval_in_range(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    x: &Expr,
+    start: &Expr,
+    end: &Expr,
+) -> Result<Value> {
+    let x = eval_expr(state, env, x)?;
+    let start = eval_expr(state, env, start)?;
+    let end = eval_expr(state, env, end)?;
+    match (x, start, end) {
+        (Value::Number(x), Value::Number(start), Value::Number(end)) => {
+            Ok(Value::Boolean(start <= x && x < end))
+        }
+        (x, start, end) => {
+            bail!("Error: ContainedIn range expects numbers, found {x:?} in {start:?}..{end:?}")
+        }
     }
 }
-fn eval_print(env: Environment, expr: Box<Expr>) -> Result<Environment> {
-    let value = eval_expr(&env, expr)?;
-    match value {
-        Value::String(s) => println!("{s}"),
-        Value::Number(n) => println!("{n}"),
-        Value::Boolean(b) => println!("{b}"),
-        _ => unimplemented!("{value:?}"),
+/// The values of `print a, b, c;` separated by spaces.
+fn render_print_arguments(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    arguments: &[Expr],
+) -> Result<String> {
+    let values = arguments
+        .iter()
+        .map(|argument| Ok(eval_expr(state, env, argument)?.to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(values.join(" "))
+}
+
+/// Dumps every variable in scope to stderr, sorted by name.
+fn eval_debug(state: &mut State, env: &EnvironmentStack) -> Result<()> {
+    // Inner scopes are inserted last, so shadowed variables are hidden.
+    let visible: BTreeMap<&String, &Value> = env.iter().flatten().collect();
+    for (name, value) in visible {
+        let rendered = match value {
+            Value::String(s) => format!("{s:?}"),
+            value => value.to_string(),
+        };
+        state
+            .out
+            .write_error_line(&format!("{name} = {rendered} ({})", value.type_name()))?;
     }
-    Ok(env)
+    Ok(())
 }
 
-fn eval_if(env: Environment, expr: Box<Expr>, body: Box<Statement>) -> Result<Environment> {
-    Ok(if eval_expr(&env, expr)? == Value::Boolean(true) {
-        eval(env, *body)?
+fn eval_swap(env: &mut EnvironmentStack, left: &str, right: &str) -> Result<()> {
+    let lookup = |name: &str| {
+        lookup(env, name)
+            .cloned()
+            .with_context(|| format!("swap: variable {name:?} not found"))
+    };
+    let (left_value, right_value) = (lookup(left)?, lookup(right)?);
+    *lookup_mut(env, left).unwrap() = right_value;
+    *lookup_mut(env, right).unwrap() = left_value;
+    Ok(())
+}
+
+/// Evaluates `body` in a new scope, which starts with the variables in `scope`.
+fn eval_in_scope(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    scope: Environment,
+    body: &Statement,
+) -> Result<()> {
+    env.push(scope);
+    // Popped even if `body` fails, so that the scope doesn't outlive it.
+    let result = eval(state, env, body);
+    env.pop();
+    result
+}
+
+/// Runs `statements` one after the other, until one of them jumps.
+fn eval_statements(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    statements: &[Statement],
+) -> Result<()> {
+    for statement in statements {
+        eval(state, env, statement)?;
+        if state.jump.is_some() {
+            break;
+        }
+    }
+    Ok(())
+}
+/// The condition of an `if` or a `while`, which has to be a boolean: `while n` is most
+/// likely a mistake for `while n > 0`.
+fn eval_condition(state: &mut State, env: &mut EnvironmentStack, expr: &Expr) -> Result<bool> {
+    match eval_expr(state, env, expr)? {
+        Value::Boolean(b) => Ok(b),
+        value => bail!(
+            "Error: condition must be a boolean, got {}",
+            value.type_name()
+        ),
+    }
+}
+fn eval_if(
+    state: &mut State,
+    env: &mut EnvironmentStack,
+    expr: &Expr,
+    body: &Statement,
+    else_body: &Option<Box<Statement>>,
+) -> Result<()> {
+    if eval_condition(state, env, expr)? {
+        eval(state, env, body)
+    } else if let Some(else_body) = else_body {
+        eval(state, env, else_body)
     } else {
-        env
-    })
+        Ok(())
+    }
 }
-fn eval(env: Environment, expr: Statement) -> Result<Environment> {
-    let ret = match expr {
+/// To be called after each iteration of a loop: takes a `break` or a `continue`, and tells
+/// whether the loop is over.
+fn end_of_iteration(state: &mut State) -> bool {
+    match state.jump {
+        Some(Jump::Break) => {
+            state.jump = None;
+            true
+        }
+        Some(Jump::Continue) => {
+            state.jump = None;
+            false
+        }
+        Some(Jump::Return(_)) => true,
+        None => false,
+    }
+}
+/// The scope of a loop body, binding the iteration index if the loop names one.
+fn loop_scope(index: &Option<String>, iteration: i64) -> Environment {
+    index
+        .iter()
+        .map(|index| (index.clone(), Value::Number(iteration)))
+        .collect()
+}
+fn eval(state: &mut State, env: &mut EnvironmentStack, expr: &Statement) -> Result<()> {
+    match expr {
         Statement::Assignment(variable_name, expr, is_let) => {
-            evaluate_assignment(env, variable_name, expr, is_let)?
+            evaluate_assignment(state, env, variable_name, expr, *is_let)?
+        }
+        Statement::IndexedAssignment(variable_name, index, expr) => {
+            evaluate_indexed_assignment(state, env, variable_name, index, expr)?
+        }
+        Statement::Print(arguments) => {
+            let line = render_print_arguments(state, env, arguments)?;
+            state.out.write_line(&line)?;
+        }
+        // Like `print`, for diagnostics that shouldn't mix with the output of the program.
+        Statement::EPrint(arguments) => {
+            let line = render_print_arguments(state, env, arguments)?;
+            state.out.write_error_line(&line)?;
+        }
+        Statement::Write(arguments) => {
+            let text = render_print_arguments(state, env, arguments)?;
+            state.out.write(&text)?;
         }
-        Statement::Print(expr) => eval_print(env, expr)?,
-        Statement::If(expr, body) => eval_if(env, expr, body)?,
-        Statement::While(expr, body) => {
-            let mut env = env;
-            while eval_expr(&env, expr.clone())? == Value::Boolean(true) {
-                env = eval(env, *body.clone())?;
+        Statement::If(expr, body, else_body) => eval_if(state, env, expr, body, else_body)?,
+        Statement::While(expr, index, body) => {
+            let mut iteration = 0;
+            while eval_condition(state, env, expr)? {
+                eval_in_scope(state, env, loop_scope(index, iteration), body)?;
+                iteration += 1;
+                if end_of_iteration(state) {
+                    break;
+                }
             }
-            env
         }
-        Statement::Block(block) => {
-            let mut env = env;
-            for expr in block {
-                env = eval(env, expr)?;
+        Statement::DoWhile(body, expr) => loop {
+            eval(state, env, body)?;
+            if end_of_iteration(state) || !eval_condition(state, env, expr)? {
+                break;
+            }
+        },
+        Statement::Repeat(count, index, body) => {
+            let Value::Number(count) = eval_expr(state, env, count)? else {
+                bail!("Error: repeat count must be a number");
+            };
+            for iteration in 0..count {
+                eval_in_scope(state, env, loop_scope(index, iteration), body)?;
+                if end_of_iteration(state) {
+                    break;
+                }
             }
-            env
         }
-        _ => unimplemented!("{expr:?}"),
-    };
-    Ok(ret)
+        Statement::For(variable, start, end, body) => {
+            let (start, end) = match (eval_expr(state, env, start)?, eval_expr(state, env, end)?) {
+                (Value::Number(start), Value::Number(end)) => (start, end),
+                (start, end) => {
+                    bail!("Error: for loop range expects numbers, found {start:?}..{end:?}")
+                }
+            };
+            for i in start..end {
+                let scope = Environment::from([(variable.clone(), Value::Number(i))]);
+                eval_in_scope(state, env, scope, body)?;
+                if end_of_iteration(state) {
+                    break;
+                }
+            }
+        }
+        Statement::ForEntries(key, value, map, body) => {
+            let map = match eval_expr(state, env, map)? {
+                Value::Map(map) => map,
+                value => bail!(
+                    "Error: for (key, value) expects a map, found a {}",
+                    value.type_name()
+                ),
+            };
+            // In key order, so that the iteration doesn't depend on how the map is stored.
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            for (entry_key, entry_value) in entries {
+                let scope = Environment::from([
+                    (key.clone(), Value::from(entry_key.as_str())),
+                    (value.clone(), entry_value.clone()),
+                ]);
+                eval_in_scope(state, env, scope, body)?;
+                if end_of_iteration(state) {
+                    break;
+                }
+            }
+        }
+        Statement::Swap(left, right) => eval_swap(env, left, right)?,
+        Statement::Debug => eval_debug(state, env)?,
+        Statement::Assert(expr) => match eval_expr(state, env, expr)? {
+            Value::Boolean(true) => {}
+            Value::Boolean(false) => bail!("Error: assertion failed"),
+            value => bail!("Error: assert expects a boolean, found {value:?}"),
+        },
+        Statement::Empty => {}
+        Statement::AtLine(line, statement) => {
+            let outer = state.line.replace(*line);
+            eval(state, env, statement)?;
+            state.line = outer;
+        }
+        Statement::Break => state.jump = Some(Jump::Break),
+        Statement::Continue => state.jump = Some(Jump::Continue),
+        Statement::FunctionDef(name, parameters, body) => {
+            let function = Function {
+                parameters: parameters.clone(),
+                body: (**body).clone(),
+            };
+            state.functions.insert(name.clone(), Rc::new(function));
+        }
+        Statement::Return(expr) => {
+            let value = eval_expr(state, env, expr)?;
+            state.jump = Some(Jump::Return(value));
+        }
+        Statement::Expression(expr) => {
+            eval_expr(state, env, expr)?;
+        }
+        Statement::Block(block) => {
+            env.push(Environment::new());
+            let result = eval_statements(state, env, block);
+            env.pop();
+            result?
+        }
+    }
+    Ok(())
+}
+
+/// An interpreter whose variables outlive a single program, so the REPL can run one line at a time.
+pub struct Interpreter {
+    env: EnvironmentStack,
+    state: State,
+    /// Whether a failing statement leaves the variables as they were before it.
+    roll_back_failures: bool,
 }
-fn inner_run(program: Vec<Statement>) -> Result<Environment> {
-    let mut env: Environment = HashMap::new();
-    for expr in program {
-        env = eval(env, expr)?;
+
+impl Interpreter {
+    pub fn new(options: RunOptions) -> Self {
+        Self::with_output(
+            Box::new(std::io::stdout()),
+            Box::new(std::io::stderr()),
+            options,
+        )
+    }
+    pub fn with_output(
+        stdout: Box<dyn Write>,
+        stderr: Box<dyn Write>,
+        options: RunOptions,
+    ) -> Self {
+        Interpreter {
+            env: EnvironmentStack::default(),
+            roll_back_failures: options.continue_on_error,
+            state: State::new(
+                OutputSink::new(stdout, stderr, options.max_output_bytes),
+                &options,
+            ),
+        }
+    }
+    /// Makes a failing statement leave the variables as they were before it, so that a session
+    /// can go on after an error. It costs a copy of the globals per statement that writes to them.
+    pub fn roll_back_failures(&mut self) {
+        self.roll_back_failures = true;
+    }
+    /// Runs `program`, stopping at the first failing statement unless `continue_on_error` is set.
+    pub fn run(&mut self, program: Vec<Statement>) -> Result<()> {
+        let mut failures = 0;
+        for statement in &program {
+            let Err(e) = self.run_statement(statement) else {
+                continue;
+            };
+            if !self.state.options.continue_on_error {
+                return Err(e);
+            }
+            self.state.out.write_error_line(&e.to_string())?;
+            failures += 1;
+        }
+        match failures {
+            0 => Ok(()),
+            1 => bail!("Error: 1 statement failed"),
+            failures => bail!("Error: {failures} statements failed"),
+        }
+    }
+    fn run_statement(&mut self, statement: &Statement) -> Result<()> {
+        // Only taken when needed: sharing the globals with it makes the first write copy them.
+        let snapshot = self.roll_back_failures.then(|| self.env.clone());
+        if let Err(e) = eval(&mut self.state, &mut self.env, statement) {
+            if let Some(snapshot) = snapshot {
+                self.env = snapshot;
+            }
+            return Err(match self.state.line.take() {
+                Some(line) => {
+                    // One prefix is enough: not `runtime error at line 2: Error: ...`.
+                    let message = e.to_string();
+                    let message = message.strip_prefix("Error: ").unwrap_or(&message);
+                    anyhow!("runtime error at line {line}: {message}")
+                }
+                None => e,
+            });
+        }
+        self.state.check_no_jump()
+    }
+    pub fn options(&self) -> &RunOptions {
+        &self.state.options
+    }
+    pub fn eval_expression(&mut self, expr: &Expr) -> Result<Value> {
+        eval_expr(&mut self.state, &mut self.env, expr)
+    }
+    /// Reports the function call counts, if profiling.
+    pub fn report_profile(&mut self) -> Result<()> {
+        self.state.report_profile()
     }
-    Ok(env)
 }
 
-pub fn run(program: Vec<Statement>) -> Result<()> {
-    inner_run(program)?;
-    Ok(())
+pub fn run(program: Vec<Statement>, options: RunOptions) -> Result<()> {
+    let mut interpreter = Interpreter::new(options);
+    interpreter.run(program)?;
+    interpreter.report_profile()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A writer whose contents can still be inspected after it's been handed to an `OutputSink`.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs `program`, returning the global scope once it's done.
+    fn inner_run(program: Vec<Statement>, out: OutputSink) -> Result<Environment> {
+        let mut interpreter = Interpreter {
+            env: EnvironmentStack::default(),
+            state: State::new(out, &RunOptions::default()),
+            roll_back_failures: false,
+        };
+        interpreter.run(program)?;
+        Ok(Rc::unwrap_or_clone(interpreter.env.globals))
+    }
+    fn discard_output() -> OutputSink {
+        OutputSink::new(Box::new(std::io::sink()), Box::new(std::io::sink()), None)
+    }
     #[test]
     fn test_eval() {
         let program = vec![
@@ -193,13 +1445,1137 @@ mod tests {
                 true,
             ),
         ];
-        let env = inner_run(program).unwrap();
+        let env = inner_run(program, discard_output()).unwrap();
         let mut expected_env = HashMap::new();
         expected_env.insert("a".to_string(), Value::Number(1));
         expected_env.insert("b".to_string(), Value::Number(2));
         assert_eq!(env, expected_env);
     }
 
+    #[test]
+    fn test_value_conversions() {
+        assert_eq!(Value::from(42), Value::Number(42));
+        assert_eq!(i64::try_from(Value::from(42)).unwrap(), 42);
+        assert!(bool::try_from(Value::from(true)).unwrap());
+        assert_eq!(
+            String::try_from(Value::from("abc")).unwrap(),
+            "abc".to_string()
+        );
+        assert_eq!(
+            String::try_from(Value::from("abc".to_string())).unwrap(),
+            "abc"
+        );
+        let n: Result<i64> = Value::from("abc").try_into();
+        assert!(n.is_err());
+        let array = Value::from(vec![Value::from(1), Value::from("a")]);
+        assert_eq!(
+            Vec::<Value>::try_from(array).unwrap(),
+            vec![Value::Number(1), Value::from("a")]
+        );
+        assert!(Vec::<Value>::try_from(Value::from(1)).is_err());
+    }
+
+    fn run_source(source: &str) -> Result<Environment> {
+        let tokens = crate::lexer::parse(source)?;
+        let program = crate::parser::parse_input(tokens)?;
+        inner_run(program, discard_output())
+    }
+
+    /// Runs `source`, returning what it printed to stdout and stderr.
+    fn run_capturing(source: &str) -> Result<(String, String)> {
+        let tokens = crate::lexer::parse(source)?;
+        let program = crate::parser::parse_input(tokens)?;
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+        let out = OutputSink::new(Box::new(stdout.clone()), Box::new(stderr.clone()), None);
+        inner_run(program, out)?;
+        Ok((stdout.contents(), stderr.contents()))
+    }
+
+    #[test]
+    fn test_max_output() {
+        let tokens = crate::lexer::parse("let i := 0; while i < 100 { print i; i := i + 1; }");
+        let program = crate::parser::parse_input(tokens.unwrap()).unwrap();
+        let buffer = SharedBuffer::default();
+        let out = OutputSink::new(
+            Box::new(buffer.clone()),
+            Box::new(std::io::sink()),
+            Some(10),
+        );
+        let err = inner_run(program, out).unwrap_err();
+        assert!(err.to_string().contains("output limit exceeded"));
+        assert_eq!(buffer.contents(), "0\n1\n2\n3\n4\n");
+
+        // Only stdout counts towards the limit.
+        let tokens = crate::lexer::parse(r#"eprint "a long diagnostic"; debug; print 1234;"#);
+        let program = crate::parser::parse_input(tokens.unwrap()).unwrap();
+        let out = OutputSink::new(
+            Box::new(std::io::sink()),
+            Box::new(std::io::sink()),
+            Some(5),
+        );
+        inner_run(program, out).unwrap();
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let env = run_source("let a := 3 <= 3; let b := 4 > 5; let c := 5 >= 2;").unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("b"), Some(&Value::Boolean(false)));
+        assert_eq!(env.get("c"), Some(&Value::Boolean(true)));
+
+        let source = r#"
+            let a := "apple" < "banana";
+            let b := "b" < "a" == false;
+            let c := "abc" <= "abd" && "abc" >= "abc" && "ab" < "abc";
+            let d := "Zebra" > "apple";
+        "#;
+        let env = run_source(source).unwrap();
+        for name in ["a", "b", "c"] {
+            assert_eq!(env.get(name), Some(&Value::Boolean(true)), "{name}");
+        }
+        // By code point, so upper case comes first.
+        assert_eq!(env.get("d"), Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn test_assign_boolean_results() {
+        let source = "
+            let x := 1; let y := 2;
+            let lt := x < y; let le := x <= y; let gt := x > y; let ge := x >= y;
+            let eq := x == y; let ne := x != y;
+            let and := lt && ge; let or := lt || ge; let not := !lt;
+            print lt; print ge; print and; print or; print not;
+        ";
+        let (stdout, _) = run_capturing(source).unwrap();
+        assert_eq!(stdout, "true\nfalse\nfalse\ntrue\nfalse\n");
+        let env = run_source(source).unwrap();
+        for (name, expected) in [
+            ("lt", true),
+            ("le", true),
+            ("gt", false),
+            ("ge", false),
+            ("eq", false),
+            ("ne", true),
+        ] {
+            assert_eq!(env.get(name), Some(&Value::Boolean(expected)), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let env = run_source("let a := 2 + 3 * 4; let b := 1 + 2 + 3; let c := 4 > 5 == false;");
+        let env = env.unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Number(14)));
+        assert_eq!(env.get("b"), Some(&Value::Number(6)));
+        assert_eq!(env.get("c"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn test_grouping() {
+        let env = run_source("let a := (1 + 2) * 3 == 9;").unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        assert!(run_source("let a := (1 + 2;").is_err());
+    }
+
+    #[test]
+    fn test_else() {
+        let source = r#"
+let taken := 0;
+if 1 == 2 { taken := 1; } else { taken := 2; }
+let skipped := 0;
+if 1 == 1 { skipped := 1; } else { skipped := 2; }
+let chained := 0;
+if 1 == 2 { chained := 1; } else if 2 == 2 { chained := 2; } else { chained := 3; }
+"#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("taken"), Some(&Value::Number(2)));
+        assert_eq!(env.get("skipped"), Some(&Value::Number(1)));
+        assert_eq!(env.get("chained"), Some(&Value::Number(2)));
+    }
+
+    #[test]
+    fn test_in_range() {
+        let env = run_source("let a := 5 in 0..10; let b := 10 in 0..10; let c := 0 in 0..2 + 1;");
+        let env = env.unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("b"), Some(&Value::Boolean(false)));
+        assert_eq!(env.get("c"), Some(&Value::Boolean(true)));
+        assert!(run_source(r#"let a := "5" in 0..10;"#).is_err());
+    }
+
+    #[test]
+    fn test_swap() {
+        let env = run_source("let a := 1; let b := 2; swap a, b;").unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Number(2)));
+        assert_eq!(env.get("b"), Some(&Value::Number(1)));
+        assert!(run_source("let a := 1; swap a, b;").is_err());
+        assert!(run_source("let b := 1; swap a, b;").is_err());
+    }
+
+    #[test]
+    fn test_logical_or() {
+        let env = run_source("let a := true || false == true; let b := false || 1 == 2;");
+        let env = env.unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("b"), Some(&Value::Boolean(false)));
+        // The right operand is never evaluated, so the undefined variable isn't an error.
+        let env = run_source("let c := true || undefined_variable;").unwrap();
+        assert_eq!(env.get("c"), Some(&Value::Boolean(true)));
+        assert!(run_source("let d := false || undefined_variable;").is_err());
+        assert!(run_source("let e := 1 || true;").is_err());
+    }
+
+    #[test]
+    fn test_logical_and() {
+        let source = r#"
+let s := "abx";
+let index := 0;
+while index < 3 && s[index] != "x" {
+    index := index + 1;
+}
+let a := true && 1 < 2;
+"#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("index"), Some(&Value::Number(2)));
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        // The right operand is never evaluated, so the undefined variable isn't an error.
+        let env = run_source("let b := false && undefined_variable;").unwrap();
+        assert_eq!(env.get("b"), Some(&Value::Boolean(false)));
+        assert!(run_source("let c := true && undefined_variable;").is_err());
+        assert!(run_source("let d := true && 1;").is_err());
+    }
+
+    #[test]
+    fn test_null_coalesce() {
+        let source = "
+            let a := null ?? 5;
+            let b := 3 ?? 5;
+            let c := null ?? null ?? 7;
+            let d := null ?? 5 == 5;
+            let e := null;
+            let f := 3 ?? undefined_variable;
+        ";
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Number(5)));
+        assert_eq!(env.get("b"), Some(&Value::Number(3)));
+        assert_eq!(env.get("c"), Some(&Value::Number(7)));
+        assert_eq!(env.get("d"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("e"), Some(&Value::Null));
+        // `b` is evaluated only when `a` is null.
+        assert_eq!(env.get("f"), Some(&Value::Number(3)));
+        assert!(run_source("let g := null ?? undefined_variable;").is_err());
+    }
+
+    #[test]
+    fn test_not() {
+        let env =
+            run_source("let a := !false == true; let b := !(1 == 2) == true; let c := !true;");
+        let env = env.unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("b"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("c"), Some(&Value::Boolean(false)));
+        assert!(run_source("let d := !1;").is_err());
+    }
+
+    #[test]
+    fn test_negation() {
+        let env = run_source(
+            "let a := -5; let b := -(2 + 3); let c := 0 - -5 == 5; let x := 3; let d := -x + 2; let e := 10 - 2 - 3;",
+        )
+        .unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Number(-5)));
+        assert_eq!(env.get("b"), Some(&Value::Number(-5)));
+        assert_eq!(env.get("c"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("d"), Some(&Value::Number(-1)));
+        assert_eq!(env.get("e"), Some(&Value::Number(5)));
+        assert!(run_source("let f := -true;").is_err());
+        assert!(run_source(r#"let g := "a" - "b";"#).is_err());
+    }
+
+    #[test]
+    fn test_empty_statement() {
+        let source = ";; let a := 1;; if a == 1 { ; a := 2; ; } ; print a;;";
+        let (stdout, _) = run_capturing(source).unwrap();
+        assert_eq!(stdout, "2\n");
+    }
+
+    #[test]
+    fn test_typeof() {
+        let source = r#"let a := typeof(5) == "number"; let b := typeof("x") == "string";
+            let c := typeof(true) == "boolean"; let d := typeof(1 < 2);"#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("b"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("c"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("d"), Some(&Value::from("boolean")));
+    }
+
+    #[test]
+    fn test_times() {
+        let source = r#"
+            fn square(i) { return i * i; }
+            fn one() { return 1; }
+            let squares := times(4, "square");
+            let ones := times(2, "one");
+            let none := times(0, "square");
+        "#;
+        let env = run_source(source).unwrap();
+        let numbers = |ns: &[i64]| {
+            Some(Value::from(
+                ns.iter().copied().map(Value::Number).collect::<Vec<_>>(),
+            ))
+        };
+        assert_eq!(env.get("squares").cloned(), numbers(&[0, 1, 4, 9]));
+        assert_eq!(env.get("ones").cloned(), numbers(&[1, 1]));
+        assert_eq!(env.get("none").cloned(), numbers(&[]));
+        assert!(run_source(r#"let a := times(2, "missing");"#).is_err());
+        assert!(run_source(r#"fn add(a, b) { return a + b; } let a := times(2, "add");"#).is_err());
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let source = "let a := 12 & 10; let b := 12 | 3; let c := 5 & 1 == 1; let d := true | false && false; let e := -1 & 255;";
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Number(8)));
+        assert_eq!(env.get("b"), Some(&Value::Number(15)));
+        assert_eq!(env.get("c"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("d"), Some(&Value::Boolean(false)));
+        assert_eq!(env.get("e"), Some(&Value::Number(255)));
+        assert!(run_source("let f := 1 & true;").is_err());
+    }
+
+    #[test]
+    fn test_error_line() {
+        let run_lines = |source: &str| {
+            let tokens = crate::lexer::parse_with_positions(source).unwrap();
+            let program = crate::parser::parse_program(tokens).unwrap();
+            inner_run(program, discard_output())
+                .unwrap_err()
+                .to_string()
+        };
+        let source = "let a := 1;\nwhile a < 3 {\n    a := a + 1;\n    print undefined;\n}\n";
+        assert_eq!(
+            run_lines(source),
+            "runtime error at line 4: variable \"undefined\" not found"
+        );
+        // The line inside the function, rather than the one of the call.
+        let source = "fn f() {\n    return 1 / 0;\n}\nlet a := 1;\nlet b := f();\n";
+        assert_eq!(
+            run_lines(source),
+            "runtime error at line 2: Division by zero"
+        );
+        let source = "fn f() { return 1; }\nlet a := f() + undefined;\n";
+        assert_eq!(
+            run_lines(source),
+            "runtime error at line 2: variable \"undefined\" not found"
+        );
+    }
+
+    #[test]
+    fn test_print_arguments() {
+        let (stdout, stderr) =
+            run_capturing(r#"print "sum:", 42; print 1; print "a", [1], 1 < 2; eprint "x", null;"#)
+                .unwrap();
+        assert_eq!(stdout, "sum: 42\n1\na [1] true\n");
+        assert_eq!(stderr, "x null\n");
+        assert!(run_source("print 1,;").is_err());
+        assert!(run_source("print 1 2;").is_err());
+    }
+
+    #[test]
+    fn test_write() {
+        let (stdout, _) = run_capturing(r#"write "name: "; write "a", 1; print "!";"#).unwrap();
+        assert_eq!(stdout, "name: a 1!\n");
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let env = run_source(
+            r#"let x := 5; x += 3; let y := 10; y -= 2 + 1; let z := 2; z *= z + 1; let s := "a"; s += "b";"#,
+        )
+        .unwrap();
+        assert_eq!(env.get("x"), Some(&Value::Number(8)));
+        assert_eq!(env.get("y"), Some(&Value::Number(7)));
+        assert_eq!(env.get("z"), Some(&Value::Number(6)));
+        assert_eq!(env.get("s"), Some(&Value::from("ab")));
+        assert!(run_source("undeclared += 1;").is_err());
+    }
+
+    #[test]
+    fn test_string_repetition() {
+        let env = run_source(r#"let a := "ab" * 3 == "ababab"; let b := "x" * 0; let c := "5" * 2; let d := 2 * "5";"#)
+            .unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("b"), Some(&Value::from("")));
+        assert_eq!(env.get("c"), Some(&Value::from("55")));
+        assert_eq!(env.get("d"), Some(&Value::Number(10)));
+        let err = run_source(r#"let e := "ab" * -1;"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: cannot repeat a string a negative number of times (-1)"
+        );
+        assert!(run_source(r#"let f := "ab" * 9223372036854775807;"#).is_err());
+    }
+
+    #[test]
+    fn test_continue_on_error() {
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+        let options = RunOptions {
+            continue_on_error: true,
+            ..RunOptions::default()
+        };
+        let mut interpreter =
+            Interpreter::with_output(Box::new(stdout.clone()), Box::new(stderr.clone()), options);
+        let source = "let a := undefined; print 1; let b := 1 / 0; let c := 2; print c; break;";
+        let program = crate::parser::parse_input(crate::lexer::parse(source).unwrap()).unwrap();
+        let err = interpreter.run(program).unwrap_err();
+        assert_eq!(err.to_string(), "Error: 3 statements failed");
+        assert_eq!(stdout.contents(), "1\n2\n");
+        assert_eq!(
+            stderr.contents(),
+            "variable \"undefined\" not found\nError: Division by zero\nError: 'break' outside of a loop\n"
+        );
+        assert_eq!(interpreter.env.globals.get("a"), None);
+        assert_eq!(interpreter.env.globals.get("c"), Some(&Value::Number(2)));
+
+        let (stdout, _) = run_capturing("print 1;").unwrap();
+        assert_eq!(stdout, "1\n");
+        assert!(run_source("let a := undefined; let b := 1;").is_err());
+    }
+
+    #[test]
+    fn test_do_while() {
+        let source = "let runs := 0; do { runs := runs + 1; } while false;
+            let n := 0; do { n := n + 1; if n == 2 { continue; } } while n < 5;
+            let m := 0; do { m := m + 1; break; } while true;";
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("runs"), Some(&Value::Number(1)));
+        assert_eq!(env.get("n"), Some(&Value::Number(5)));
+        assert_eq!(env.get("m"), Some(&Value::Number(1)));
+        assert!(run_source("do { } while 1;").is_err());
+        assert!(run_source("do { } until false;").is_err());
+    }
+
+    #[test]
+    fn test_variable_suggestions() {
+        let err = run_source("let index := 0; let count := 1; print indx;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "variable \"indx\" not found, did you mean \"index\"?"
+        );
+        // Inner scopes are searched too.
+        let err = run_source("if true { let total := 1; total := totl + 1; }").unwrap_err();
+        assert!(
+            err.to_string().ends_with("did you mean \"total\"?"),
+            "{err}"
+        );
+        let err = run_source("let index := 0; print something;").unwrap_err();
+        assert_eq!(err.to_string(), "variable \"something\" not found");
+        let err = run_source("let x := 0; print y;").unwrap_err();
+        assert_eq!(err.to_string(), "variable \"y\" not found");
+
+        assert_eq!(edit_distance("indx", "index"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("same", "same"), 0);
+    }
+
+    #[test]
+    fn test_boolean_conditions() {
+        let env = run_source("let a := 0; if 1 < 2 { a := 1; } while a < 3 { a := a + 1; }");
+        assert_eq!(env.unwrap().get("a"), Some(&Value::Number(3)));
+        let err = run_source("let a := 1; if a { a := 2; }").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: condition must be a boolean, got number"
+        );
+        let err = run_source(r#"while "yes" { }"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: condition must be a boolean, got string"
+        );
+        assert!(run_source("if null { } else { }").is_err());
+    }
+
+    #[test]
+    fn test_assert() {
+        assert!(run_source("assert 1 == 1; assert true && !false;").is_ok());
+        let err = run_source("assert 1 == 2;").unwrap_err();
+        assert_eq!(err.to_string(), "Error: assertion failed");
+        assert!(run_source("assert 1;").is_err());
+
+        let tokens = crate::lexer::parse_with_positions("let a := 1;\nassert a > 1;").unwrap();
+        let program = crate::parser::parse_program(tokens).unwrap();
+        let err = inner_run(program, discard_output()).unwrap_err();
+        assert_eq!(err.to_string(), "runtime error at line 2: assertion failed");
+    }
+
+    #[test]
+    fn test_eprint() {
+        let (stdout, stderr) =
+            run_capturing(r#"print "out"; eprint "warning: " + "low"; print 2;"#).unwrap();
+        assert_eq!(stdout, "out\n2\n");
+        assert_eq!(stderr, "warning: low\n");
+    }
+
+    #[test]
+    fn test_reduce() {
+        let source = r#"
+            fn add(a, b) { return a + b; }
+            fn join(a, b) { return a + "," + b; }
+            let sum := reduce([1, 2, 3, 4], "add", 0);
+            let joined := reduce(["b", "c"], "join", "a");
+            let empty := reduce([], "add", 7);
+        "#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("sum"), Some(&Value::Number(10)));
+        assert_eq!(env.get("joined"), Some(&Value::from("a,b,c")));
+        assert_eq!(env.get("empty"), Some(&Value::Number(7)));
+        assert!(run_source(r#"let a := reduce([1], "missing", 0);"#).is_err());
+        assert!(run_source(r#"fn one(a) { return a; } let a := reduce([1], "one", 0);"#).is_err());
+        assert!(
+            run_source(r#"fn add(a, b) { return a + b; } let a := reduce(1, "add", 0);"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_maps() {
+        let source = r#"
+            let m := { "a": 1, "b": "two" };
+            let a := m["a"];
+            m["c"] := 3;
+            m["a"] := m["a"] + 10;
+            let empty := {};
+            let copy := m;
+            copy["d"] := 4;
+            if m == {} { print "empty"; }
+            print m, typeof(m), empty;
+        "#;
+        let (stdout, _) = run_capturing(source).unwrap();
+        assert_eq!(stdout, "{\"a\": 11, \"b\": \"two\", \"c\": 3} map {}\n");
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Number(1)));
+        let Some(Value::Map(copy)) = env.get("copy") else {
+            panic!("expected a map");
+        };
+        assert_eq!(copy.len(), 4);
+
+        let err = run_source(r#"let m := { "a": 1 }; let b := m["b"];"#).unwrap_err();
+        assert_eq!(err.to_string(), "Error: key \"b\" not found in the map");
+        assert!(run_source(r#"let m := { 1: 1 };"#).is_err());
+        assert!(run_source(r#"let m := {}; m[0] := 1;"#).is_err());
+        assert!(run_source(r#"let m := { "a" 1 };"#).is_err());
+    }
+
+    #[test]
+    fn test_block_expression() {
+        let source = r#"
+            let a := 5;
+            let y := { let t := a * 2; t + 1 };
+            let z := { a };
+            let nested := { let t := { let u := 1; u + 1 }; t * 10; };
+            let m := { "t": 1 };
+        "#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("y"), Some(&Value::Number(11)));
+        assert_eq!(env.get("z"), Some(&Value::Number(5)));
+        assert_eq!(env.get("nested"), Some(&Value::Number(20)));
+        assert_eq!(env.get("t"), None);
+        assert!(matches!(env.get("m"), Some(Value::Map(_))));
+
+        // Assignments inside the block update the outer variables.
+        let (stdout, _) = run_capturing("let x := 1; let y := { x := 5; x }; print x, y;").unwrap();
+        assert_eq!(stdout, "5 5\n");
+        let source = "if true { let n := 1; let m := { n += 1; n * 10 }; print n, m; }";
+        let (stdout, _) = run_capturing(source).unwrap();
+        assert_eq!(stdout, "2 20\n");
+
+        let err = run_source("let y := { let t := 1; };").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: a block used as a value must end with an expression"
+        );
+        assert!(run_source("let y := { print 1; };").is_err());
+
+        // Telling blocks from maps doesn't parse anything twice, deep nesting stays fast.
+        let nested = format!("let y := {}1{};", "{ (".repeat(40), ") }".repeat(40));
+        assert_eq!(
+            run_source(&nested).unwrap().get("y"),
+            Some(&Value::Number(1))
+        );
+    }
+
+    #[test]
+    fn test_slices() {
+        let source = r#"let s := "abcdef"; let a := s[1..4]; let b := "abcdef"[1..4] == "bcd";
+            let c := s[0..0]; let d := [1, 2, 3][1..3]; let e := "héllo"[1..3]; let f := s[4..6];"#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::from("bcd")));
+        assert_eq!(env.get("b"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("c"), Some(&Value::from("")));
+        assert_eq!(
+            env.get("d"),
+            Some(&Value::from(vec![Value::Number(2), Value::Number(3)]))
+        );
+        assert_eq!(env.get("e"), Some(&Value::from("él")));
+        assert_eq!(env.get("f"), Some(&Value::from("ef")));
+        let err = run_source(r#"let s := "abc"[1..5];"#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: slice 1..5 out of bounds, the length is 3"
+        );
+        let err = run_source(r#"let s := "abc"[2..1];"#).unwrap_err();
+        assert_eq!(err.to_string(), "Error: slice 2..1 starts after its end");
+        assert!(run_source(r#"let s := "abc"[-1..1];"#).is_err());
+        assert!(run_source("let s := 5[0..1];").is_err());
+    }
+
+    #[test]
+    fn test_tee() {
+        let (stdout, stderr) =
+            run_capturing(r#"let x := tee(5, "x"); print tee(x + 1, "y") * 2;"#).unwrap();
+        assert_eq!(stdout, "12\n");
+        assert_eq!(stderr, "x: 5\ny: 6\n");
+        let env = run_source(r#"let x := tee(5, "x");"#).unwrap();
+        assert_eq!(env.get("x"), Some(&Value::Number(5)));
+        assert!(run_source("let x := tee(5);").is_err());
+    }
+
+    #[test]
+    fn test_debug() {
+        let source = r#"let b := "x"; let a := 1; let c := a < 2; debug;"#;
+        let (stdout, stderr) = run_capturing(source).unwrap();
+        assert_eq!(stdout, "");
+        assert_eq!(
+            stderr,
+            "a = 1 (number)\nb = \"x\" (string)\nc = true (boolean)\n"
+        );
+    }
+
+    #[test]
+    fn test_string_equality() {
+        let env = run_source(r#"let a := "abc" == "abc"; let b := "abc" == "abd";"#).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("b"), Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn test_loop_index() {
+        let (stdout, _) = run_capturing("repeat 5 as i { print i; }").unwrap();
+        assert_eq!(stdout, "0\n1\n2\n3\n4\n");
+
+        let env =
+            run_source("let n := 0; let last := 0; while n < 3 as i { last := i; n := n + 1; }");
+        assert_eq!(env.unwrap().get("last"), Some(&Value::Number(2)));
+        assert!(run_source(r#"repeat "5" { }"#).is_err());
+    }
+
+    #[test]
+    fn test_string_number_coercion() {
+        let env = run_source(r#"let a := "5" + 1; let b := 2 * "3";"#).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Number(6)));
+        assert_eq!(env.get("b"), Some(&Value::Number(6)));
+        let err = run_source(r#"let c := "abc" + 1;"#).unwrap_err();
+        assert!(err.to_string().contains("\"abc\""));
+        assert!(run_source(r#"let d := 2 * "x";"#).is_err());
+    }
+
+    #[test]
+    fn test_string_concatenation() {
+        let env =
+            run_source(r#"let a := "foo" + "bar"; let b := "5" + 3; let c := "" + "x";"#).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::from("foobar")));
+        assert_eq!(env.get("b"), Some(&Value::Number(8)));
+        assert_eq!(env.get("c"), Some(&Value::from("x")));
+        assert!(run_source(r#"let d := "a" + true;"#).is_err());
+    }
+
+    #[test]
+    fn test_config() {
+        let run_with = |options: RunOptions, source: &str| {
+            let program = crate::parser::parse_input(crate::lexer::parse(source)?)?;
+            Interpreter::with_output(
+                Box::new(std::io::sink()),
+                Box::new(std::io::sink()),
+                options,
+            )
+            .run(program)
+        };
+        let source = r#"
+            if config()["strict_types"] { let coerced := "5" + 1; }
+            if config()["max_output_bytes"] != 100 { let unknown := undefined_variable; }
+        "#;
+        let strict = RunOptions {
+            strict_types: true,
+            max_output_bytes: Some(100),
+            ..RunOptions::default()
+        };
+        let err = run_with(strict, source).unwrap_err();
+        assert!(err.to_string().contains("strict types are on"), "{err}");
+        let lenient = RunOptions::default();
+        let err = run_with(lenient, source).unwrap_err();
+        assert_eq!(err.to_string(), "variable \"undefined_variable\" not found");
+        let env =
+            run_source(r#"let a := config()["max_output_bytes"]; let b := "5" + 1;"#).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Null));
+        assert_eq!(env.get("b"), Some(&Value::Number(6)));
+        let (stdout, _) = run_capturing("print config();").unwrap();
+        assert_eq!(
+            stdout,
+            "{\"continue_on_error\": false, \"max_output_bytes\": null, \"profile\": false, \"promote_large_integers\": false, \"strict_types\": false, \"true_division\": false}\n"
+        );
+        assert!(run_source(r#"let a := config()["nope"];"#).is_err());
+        assert!(run_source(r#"let a := config("strict_types");"#).is_err());
+    }
+
+    #[test]
+    fn test_division() {
+        let env = run_source("let a := 7 / 2; let b := -7 / 2; let c := 2 + 9 / 3 * 2;").unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Number(3)));
+        assert_eq!(env.get("b"), Some(&Value::Number(-3)));
+        assert_eq!(env.get("c"), Some(&Value::Number(8)));
+        let err = run_source("let d := 1 / 0;").unwrap_err();
+        assert_eq!(err.to_string(), "Error: Division by zero");
+        assert!(run_source("let e := -9223372036854775807 - 1; let f := e / -1;").is_err());
+
+        let options = RunOptions {
+            true_division: true,
+            ..RunOptions::default()
+        };
+        let stdout = SharedBuffer::default();
+        let mut interpreter =
+            Interpreter::with_output(Box::new(stdout.clone()), Box::new(std::io::sink()), options);
+        let program =
+            crate::lexer::parse("print 7 / 2; print 8 / 2; print config()[\"true_division\"];");
+        interpreter
+            .run(crate::parser::parse_input(program.unwrap()).unwrap())
+            .unwrap();
+        assert_eq!(stdout.contents(), "3.5\n4.0\ntrue\n");
+        let source = r#"
+            assert 7 / 2 == 3.5;
+            assert 7 / 2 + 1 == 4.5 && 7 / 2 * 2 == 7 && 7 / 2 - 0.5 == 3;
+            assert 8 / 2 == 4 && 6 / 3 != 3 && [8 / 2] == [4] && { "a": 8 / 2 } == { "a": 4 };
+            assert 7 / 2 < 4 && 7 / 2 > 3 && 4 >= 8 / 2;
+            assert 7 / 2 % 2 == 1.5 && -(7 / 2) == -3.5;
+            assert div(7, 2) == 3 && div(-7, 2) == -4 && div(7, -2) == -4 && div(-8, 2) == -4;
+            assert div(7.5, 2) == 3;
+        "#;
+        let program = crate::parser::parse_input(crate::lexer::parse(source).unwrap()).unwrap();
+        interpreter.run(program).unwrap();
+        assert!(interpreter
+            .run(crate::parser::parse_input(crate::lexer::parse("div(1, 0);").unwrap()).unwrap())
+            .is_err());
+        assert!(interpreter
+            .run(crate::parser::parse_input(crate::lexer::parse("1.5 / 0;").unwrap()).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_floats() {
+        let env = run_source("let a := 1.5 + 1; let b := 2 * 0.25; let c := 1.5 < 2;").unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Float(2.5)));
+        assert_eq!(env.get("b"), Some(&Value::Float(0.5)));
+        assert_eq!(env.get("c"), Some(&Value::Boolean(true)));
+        // Without true division, `/` only gives a float if an operand is one.
+        let env = run_source("let d := 7 / 2; let e := 7.0 / 2; let f := div(7.0, 2);").unwrap();
+        assert_eq!(env.get("d"), Some(&Value::Number(3)));
+        assert_eq!(env.get("e"), Some(&Value::Float(3.5)));
+        assert_eq!(env.get("f"), Some(&Value::Float(3.0)));
+        assert!(run_source(r#"let g := 1.5 + "1";"#).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic_overflow() {
+        let env = run_source("let a := 9223372036854775806 + 1;").unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Number(i64::MAX)));
+        let err = run_source("let b := 9223372036854775807 + 1;").unwrap_err();
+        assert!(err.to_string().contains("arithmetic overflow"));
+        let err = run_source("let c := 4611686018427387904 * 2;").unwrap_err();
+        assert!(err.to_string().contains("arithmetic overflow"));
+    }
+
+    #[test]
+    fn test_comparisons_across_types() {
+        let env = run_source(r#"let a := 1 == "1"; let b := true != "true";"#).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(false)));
+        assert_eq!(env.get("b"), Some(&Value::Boolean(true)));
+        let err = run_source(r#"let c := 1 < "1";"#).unwrap_err();
+        assert!(err.to_string().contains("between number and string"));
+        assert!(run_source("let d := true >= false;").is_err());
+    }
+
+    #[test]
+    fn test_builtin_call() {
+        let env = run_source(r#"let a := take("hello", 1 + 1) == "he";"#).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        let env = run_source("let b := min(3, 7) == 3 && max(3, 7) == 7 && abs(0 - 5) == 5;");
+        assert_eq!(env.unwrap().get("b"), Some(&Value::Boolean(true)));
+        assert!(run_source("let b := unknown_function(1);").is_err());
+    }
+
+    #[test]
+    fn test_len_as_loop_bound() {
+        let source = r#"
+            let s := "hello";
+            let count := 0;
+            let i := 0;
+            while i < len(s) { count := count + 1; i := i + 1; }
+            let same := len("hello") == 5;
+        "#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("count"), Some(&Value::Number(5)));
+        assert_eq!(env.get("same"), Some(&Value::Boolean(true)));
+    }
+
+    #[test]
+    fn test_profile() {
+        let program = crate::parser::parse_input(
+            crate::lexer::parse(
+                r#"let i := 0; while i < len("abc") { i := i + len(take("ab", 1)); }"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let stderr = SharedBuffer::default();
+        let options = RunOptions {
+            profile: true,
+            ..RunOptions::default()
+        };
+        let mut interpreter =
+            Interpreter::with_output(Box::new(std::io::sink()), Box::new(stderr.clone()), options);
+        interpreter.run(program).unwrap();
+        interpreter.report_profile().unwrap();
+        assert_eq!(stderr.contents(), "len: 7 calls\ntake: 3 calls\n");
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let source = "
+            let i := 0; let sum := 0;
+            while true {
+                i := i + 1;
+                if i > 10 { break; }
+                if i % 2 == 0 { continue; }
+                sum := sum + i;
+            }
+            let repeated := 0;
+            repeat 10 as j {
+                if j == 3 { break; }
+                repeated := repeated + 1;
+            }
+        ";
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("i"), Some(&Value::Number(11)));
+        assert_eq!(env.get("sum"), Some(&Value::Number(1 + 3 + 5 + 7 + 9)));
+        assert_eq!(env.get("repeated"), Some(&Value::Number(3)));
+        // Only the innermost loop is left.
+        let source = "
+            let count := 0;
+            repeat 3 { repeat 3 { count := count + 1; break; } }
+        ";
+        assert_eq!(
+            run_source(source).unwrap().get("count"),
+            Some(&Value::Number(3))
+        );
+        let err = run_source("if true { break; }").unwrap_err();
+        assert_eq!(err.to_string(), "Error: 'break' outside of a loop");
+        assert!(run_source("continue;").is_err());
+    }
+
+    #[test]
+    fn test_for() {
+        let env = run_source("let sum := 0; for i in 0..5 { sum := sum + i; }").unwrap();
+        assert_eq!(env.get("sum"), Some(&Value::Number(10)));
+        // The loop variable only lives inside the loop.
+        assert_eq!(env.get("i"), None);
+        let source = "let i := 7; let n := 3; let last := 0; for i in 1..n + 1 { last := i; }";
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("i"), Some(&Value::Number(7)));
+        assert_eq!(env.get("last"), Some(&Value::Number(3)));
+        let env = run_source("let count := 0; for i in 5..0 { count := count + 1; }").unwrap();
+        assert_eq!(env.get("count"), Some(&Value::Number(0)));
+        assert!(run_source("for i in 5 { }").is_err());
+        assert!(run_source(r#"for i in 0.."a" { }"#).is_err());
+    }
+
+    #[test]
+    fn test_for_entries() {
+        let source = r#"
+            let m := { "b": 2, "c": 3, "a": 1 };
+            let keys := "";
+            let sum := 0;
+            for (k, v) in m { keys += k; sum += v; }
+            for (k, v) in m { if k == "b" { break; } m[k] := v * 10; }
+            for (k, v) in {} { keys := "never"; }
+        "#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("keys"), Some(&Value::from("abc")));
+        assert_eq!(env.get("sum"), Some(&Value::Number(6)));
+        assert_eq!(env.get("k"), None);
+        assert_eq!(env["m"].to_string(), r#"{"a": 10, "b": 2, "c": 3}"#);
+        assert!(run_source("for (k, v) in [1] { }").is_err());
+        assert!(run_source("let m := {}; for (k v) in m { }").is_err());
+    }
+
+    #[test]
+    fn test_arrays() {
+        let source = r#"let a := [10, 20, 30]; let b := a[1]; let c := [1 + 1, "x", [true]];"#;
+        let env = run_source(source).unwrap();
+        let numbers = [10, 20, 30].map(Value::Number).to_vec();
+        assert_eq!(env.get("a"), Some(&Value::from(numbers)));
+        assert_eq!(env.get("b"), Some(&Value::Number(20)));
+        assert_eq!(env["c"].to_string(), r#"[2, "x", [true]]"#);
+        let err = run_source("let a := [10, 20, 30]; let b := a[3];").unwrap_err();
+        assert_eq!(err.to_string(), "variableIndexed: index 3 out of bounds");
+        let env = run_source("let a := [] == []; let b := [1] == [2];").unwrap();
+        assert_eq!(env.get("a"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("b"), Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn test_indexed_assignment() {
+        let source = r#"
+            let arr := [1, 2, 3];
+            arr[1] := "two";
+            let s := "héllo";
+            s[1] := "e";
+            if true { arr[0] := 0; }
+        "#;
+        let env = run_source(source).unwrap();
+        let expected = vec![Value::Number(0), Value::from("two"), Value::Number(3)];
+        assert_eq!(env.get("arr"), Some(&Value::from(expected)));
+        assert_eq!(env.get("s"), Some(&Value::from("hello")));
+        let err = run_source("let arr := [1, 2, 3]; arr[3] := 4;").unwrap_err();
+        assert_eq!(err.to_string(), "Error: index 3 out of bounds of \"arr\"");
+        assert!(run_source(r#"let s := "abc"; s[3] := "d";"#).is_err());
+        assert!(run_source(r#"let s := "abc"; s[0] := "xy";"#).is_err());
+        assert!(run_source("let n := 5; n[0] := 1;").is_err());
+        assert!(run_source("missing[0] := 1;").is_err());
+    }
+
+    #[test]
+    fn test_postfix_chain() {
+        let source = r#"
+            let a := "hello".drop(1).take(3)[1];
+            let b := [[1, 2], [3, 4]][1][0];
+            let c := ("ab" + "cd")[2];
+        "#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("a"), Some(&Value::from("l")));
+        assert_eq!(env.get("b"), Some(&Value::Number(3)));
+        assert_eq!(env.get("c"), Some(&Value::from("c")));
+    }
+
+    #[test]
+    fn test_functions() {
+        let source = "
+            fn add(a, b) { return a + b; }
+            let five := add(2, 3) == 5;
+            fn factorial(n) {
+                if n == 0 { return 1; }
+                return n * factorial(n - 1);
+            }
+            let f := factorial(5);
+            fn first_even(limit) {
+                for i in 1..limit { if i % 2 == 0 { return i; } }
+            }
+            let even := first_even(10);
+            let none := first_even(2);
+            let g := 1;
+            fn read_global() { return g + 1; }
+            let h := read_global();
+        ";
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("five"), Some(&Value::Boolean(true)));
+        assert_eq!(env.get("f"), Some(&Value::Number(120)));
+        assert_eq!(env.get("even"), Some(&Value::Number(2)));
+        assert_eq!(env.get("none"), Some(&Value::Null));
+        assert_eq!(env.get("h"), Some(&Value::Number(2)));
+        // Parameters don't leak out of the call.
+        assert_eq!(env.get("limit"), None);
+        assert!(run_source("fn f(a) { return a; } let x := f(1, 2);").is_err());
+        let err = run_source("return 1;").unwrap_err();
+        assert_eq!(err.to_string(), "Error: 'return' outside of a function");
+        assert!(run_source("fn f() { break; } let x := f();").is_err());
+    }
+
+    #[test]
+    fn test_scoping() {
+        let source = r#"
+let outer := 1;
+let shadowed := 1;
+if true {
+    let inner := 2;
+    let shadowed := 2;
+    outer := inner + shadowed;
+}
+repeat 2 as i { let in_loop := i; }
+{
+    let in_block := 3;
+    let shadowed := 3;
+    outer := outer + in_block;
+}
+{{1}}
+"#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("outer"), Some(&Value::Number(7)));
+        assert_eq!(env.get("shadowed"), Some(&Value::Number(1)));
+        assert_eq!(env.get("inner"), None);
+        assert_eq!(env.get("in_loop"), None);
+        assert_eq!(env.get("in_block"), None);
+        assert_eq!(env.get("i"), None);
+        assert!(run_source("if true { let inner := 1; } print inner;").is_err());
+        let (stdout, _) = run_capturing("{ let t := 1; print t; } print 2;").unwrap();
+        assert_eq!(stdout, "1\n2\n");
+        assert!(run_source("{ let t := 1; } print t;").is_err());
+
+        // The scopes of a failing statement are gone once it's done, like those of any other.
+        let mut env = EnvironmentStack::default();
+        let mut state = State::new(discard_output(), &RunOptions::default());
+        for source in [
+            "if true { let b := 1; b := undefined; }",
+            "repeat 1 { let c := 1; c := undefined; }",
+            "for i in 0..1 { { let d := 1; d := undefined; } }",
+            "let e := { let f := 1; undefined };",
+        ] {
+            let program = crate::parser::parse_input(crate::lexer::parse(source).unwrap()).unwrap();
+            assert!(eval(&mut state, &mut env, &program[0]).is_err(), "{source}");
+            assert!(env.scopes.is_empty(), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_let_and_assignment() {
+        let err = run_source("x := 5;").unwrap_err();
+        assert!(err.to_string().contains("undeclared variable \"x\""));
+        let env = run_source("let x := 5; x := x + 1;").unwrap();
+        assert_eq!(env.get("x"), Some(&Value::Number(6)));
+        // A typo inside a block doesn't create a new variable either.
+        assert!(run_source("let index := 0; if true { idnex := 1; }").is_err());
+    }
+
+    fn eval_modulo(l: i64, r: i64) -> Result<Value> {
+        let mut env = EnvironmentStack::default();
+        let expr = Modulo(
+            Box::new(TermWrapper(Term::Integer(l))),
+            Box::new(TermWrapper(Term::Integer(r))),
+        );
+        let mut state = State::new(discard_output(), &RunOptions::default());
+        eval_expr(&mut state, &mut env, &expr)
+    }
+
+    #[test]
+    fn test_modulo() {
+        assert_eq!(eval_modulo(10, 3).unwrap(), Value::Number(1));
+        assert_eq!(eval_modulo(-10, 3).unwrap(), Value::Number(-1));
+        assert!(eval_modulo(10, 0).is_err());
+    }
+
+    #[test]
+    fn test_environment_updates() {
+        let env = run_source(
+            r#"let a := 1; let s := "abc"; let xs := [1, 2];
+            if true { let a := 10; a := a + 1; s[0] := "x"; }
+            while a < 5 { let inner := a; a := inner + 1; xs[0] := a; }
+            if a == 5 { swap a, s; } else { a := 0; }"#,
+        )
+        .unwrap();
+        assert_eq!(env.get("a"), Some(&Value::from("xbc")));
+        assert_eq!(env.get("s"), Some(&Value::Number(5)));
+        assert_eq!(
+            env.get("xs"),
+            Some(&Value::from(vec![Value::Number(5), Value::Number(2)]))
+        );
+        assert_eq!(env.get("inner"), None);
+        assert_eq!(env.len(), 3);
+
+        // The variables changed by a failing statement are restored.
+        let mut interpreter = Interpreter::with_output(
+            Box::new(std::io::sink()),
+            Box::new(std::io::sink()),
+            RunOptions::default(),
+        );
+        interpreter.roll_back_failures();
+        let program = |source: &str| {
+            crate::parser::parse_input(crate::lexer::parse(source).unwrap()).unwrap()
+        };
+        interpreter.run(program("let a := 1;")).unwrap();
+        assert!(interpreter
+            .run(program("if true { let b := 2; a := 2; a := undefined; }"))
+            .is_err());
+        assert!(interpreter.env.scopes.is_empty());
+        assert_eq!(
+            *interpreter.env.globals,
+            Environment::from([("a".to_string(), Value::Number(1))])
+        );
+    }
+
+    #[test]
+    fn test_function_calls_share_globals() {
+        let mut interpreter = Interpreter::with_output(
+            Box::new(std::io::sink()),
+            Box::new(std::io::sink()),
+            RunOptions::default(),
+        );
+        let program = |source: &str| {
+            crate::parser::parse_input(crate::lexer::parse(source).unwrap()).unwrap()
+        };
+        interpreter
+            .run(program(
+                "let a := 2; fn scale(n) { return n * a; } fn reset() { a := 0; }",
+            ))
+            .unwrap();
+        // The calls don't copy the globals, as long as they only read them.
+        let globals = Rc::as_ptr(&interpreter.env.globals);
+        interpreter
+            .run(program("print scale(1) + scale(2);"))
+            .unwrap();
+        assert_eq!(Rc::as_ptr(&interpreter.env.globals), globals);
+        // Assignments to globals outlive the call, and don't copy them either: nothing else
+        // holds them when failures aren't rolled back.
+        interpreter.run(program("reset();")).unwrap();
+        assert_eq!(interpreter.env.globals.get("a"), Some(&Value::Number(0)));
+        assert_eq!(Rc::as_ptr(&interpreter.env.globals), globals);
+
+        let source = "let c := 0; fn inc() { c := c + 1; } inc(); inc(); let d := c;";
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("d"), Some(&Value::Number(2)));
+        // The local variables of the caller aren't visible in the call.
+        let err = run_source("fn peek() { return local; } if true { let local := 1; peek(); }");
+        assert_eq!(err.unwrap_err().to_string(), "variable \"local\" not found");
+    }
+
+    #[test]
+    fn test_large_loop() {
+        let env = run_source(
+            "let sum := 0; for i in 0..300 { let j := 0; while j < 1000 { sum := sum + i % 7 + j % 3; j := j + 1; } }",
+        )
+        .unwrap();
+        assert_eq!(env.get("sum"), Some(&Value::Number(1_196_700)));
+    }
+
+    #[test]
+    fn test_cheap_clones() {
+        assert!(std::mem::size_of::<Value>() <= 24);
+        // Copies a megabyte string on every iteration unless clones share it.
+        let source = r#"
+            let s := "abcdefghij" * 100000;
+            let copy := s;
+            let n := 0;
+            for i in 0..20000 {
+                let t := s;
+                n := n + len(take(t, 3));
+            }
+        "#;
+        let env = run_source(source).unwrap();
+        assert_eq!(env.get("n"), Some(&Value::Number(60_000)));
+        let (Some(Value::String(s)), Some(Value::String(copy))) = (env.get("s"), env.get("copy"))
+        else {
+            panic!("expected two strings");
+        };
+        assert!(Rc::ptr_eq(s, copy));
+    }
+
     #[test]
     fn test_simple() {
         let simple = r#"
@@ -226,7 +2602,7 @@ while index < 42 {
         }
         index := index + 1;
     }
-    let first_digit_found_mult := first_digit_found * 10;
+    let first_digit_found_mult := to_int(first_digit_found) * 10;
     sum := sum + first_digit_found_mult;
     sum := sum + last_digit_found;
     index := index + 1;
@@ -235,7 +2611,7 @@ print sum;
 "#;
         let tokens = crate::lexer::parse(simple).unwrap();
         let program = crate::parser::parse_input(tokens).unwrap();
-        let env = inner_run(program).unwrap();
+        let env = inner_run(program, discard_output()).unwrap();
         if let Value::Number(n) = env.get("sum").unwrap() {
             assert_eq!(n, &142);
         } else {
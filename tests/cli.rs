@@ -0,0 +1,240 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::{env, fs};
+
+fn bina(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bina"))
+        .args(args)
+        .output()
+        .expect("failed to run bina")
+}
+
+fn bina_with_stdin(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bina"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run bina");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().expect("failed to run bina")
+}
+
+/// Writes `contents` to a file in the temp dir, unique per test name.
+fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = env::temp_dir().join(format!("bina-test-{}-{name}", std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn test_invalid_utf8_input() {
+    let path = temp_file("invalid-utf8.bina", b"print \"\xff\xfe\";");
+    let output = bina(&[path.to_str().unwrap()]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("input file is not valid UTF-8"), "{stderr}");
+}
+
+#[test]
+fn test_input() {
+    let path = temp_file(
+        "input.bina",
+        b"print input(); print input() + \"!\"; print len(input());",
+    );
+    let output = bina_with_stdin(&[path.to_str().unwrap()], "hello\r\nworld\n");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "hello\nworld!\n0\n"
+    );
+}
+
+#[test]
+fn test_evaluate_argument() {
+    let output = bina(&["-e", "let a := 1; print a + 2;"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+
+    let output = bina(&["-e"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Usage"));
+}
+
+#[test]
+fn test_script_arguments() {
+    let path = temp_file("args.bina", b"print args(); print len(args()[0]);");
+    let output = bina(&["--profile", path.to_str().unwrap(), "foo", "--profile"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "[\"foo\", \"--profile\"]\n3\n"
+    );
+
+    let output = bina(&["--", path.to_str().unwrap(), "bar"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "[\"bar\"]\n3\n");
+
+    let output = bina(&["-e", "print args();", "--", "a", "b"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "[\"a\", \"b\"]\n");
+}
+
+#[test]
+fn test_script_arguments_separator() {
+    let path = temp_file("args-separator.bina", b"print args();");
+    let output = bina(&[path.to_str().unwrap(), "--", "foo", "--"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "[\"foo\", \"--\"]\n"
+    );
+
+    let output = bina(&["--", path.to_str().unwrap(), "--", "foo"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "[\"foo\"]\n");
+}
+
+#[test]
+fn test_environment_variables() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bina"))
+        .args([
+            "-e",
+            r#"print getenv("BINA_GREETING"); print getenv("BINA_UNSET");
+               print env("BINA_GREETING"); print env("BINA_UNSET") == "";"#,
+        ])
+        .env("BINA_GREETING", "hello")
+        .env_remove("BINA_UNSET")
+        .output()
+        .expect("failed to run bina");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "hello\nnull\nhello\ntrue\n"
+    );
+
+    // Set on the child process, the environment of the tests is shared between threads.
+    let output = Command::new(env!("CARGO_BIN_EXE_bina"))
+        .args([
+            "-e",
+            r#"print setenv("BINA_SET", "new"); print getenv("BINA_SET");"#,
+        ])
+        .env("BINA_SET", "old")
+        .output()
+        .expect("failed to run bina");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "null\nnew\n");
+
+    let output = bina(&["-e", r#"setenv("BINA=SET", "value");"#]);
+    assert_eq!(output.status.code(), Some(1));
+    let output = bina(&["-e", "getenv(1);"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_run_directory() {
+    let directory = env::temp_dir().join(format!("bina-test-{}-scripts", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("b.bina"), "print x;").unwrap();
+    fs::write(directory.join("a.bina"), "let x := 1; print x;").unwrap();
+    fs::write(directory.join("notes.txt"), "not a script").unwrap();
+
+    // `b.bina` runs with fresh variables, so `x` is not defined there.
+    let output = bina(&[directory.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n---\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("b.bina"), "{stderr}");
+
+    fs::write(directory.join("b.bina"), "let x := 2; print x;").unwrap();
+    let output = bina(&[directory.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n---\n2\n");
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_print_ast() {
+    let output = bina(&["--ast", "-e", "print 1 + 2;"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "print\n  +\n    1\n    2\n"
+    );
+}
+
+#[test]
+fn test_print_tokens() {
+    let output = bina(&["--tokens", "-e", "let s := \"a\\\"b\";"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "1:1 Let\n1:5 Identifier(\"s\")\n1:7 Assignment\n1:10 String(\"a\\\"b\")\n1:16 Semicolon\n"
+    );
+}
+
+#[test]
+fn test_error_exit_code() {
+    let output = bina(&["-e", "print 1; print 1 / 0;"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "runtime error at line 1: Division by zero\n"
+    );
+
+    let output = bina(&["-e", "print 1 +;"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Caused by"));
+
+    let output = bina(&["-e", "print 1;"]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_check() {
+    let path = temp_file(
+        "check-valid.bina",
+        b"print \"side effect\";\nlet a := input();",
+    );
+    let output = bina(&["--check", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+
+    let path = temp_file("check-invalid.bina", b"let a := ;\nprint a;\nlet b := (1;");
+    let output = bina(&["--check", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("line 1") && stderr.contains("line 3"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_recursion_limit() {
+    let output = bina(&["-e", "fn f(n) { return f(n + 1); } print f(0);"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "runtime error at line 1: maximum call depth of 1000 exceeded in f\n"
+    );
+
+    let source =
+        "fn depth(n) { if n == 0 { return 0; } return 1 + depth(n - 1); } print depth(999);";
+    let output = bina(&["-e", source]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "999\n");
+}
+
+#[test]
+fn test_repl_input() {
+    let output = bina_with_stdin(&["--prompt", ""], "let x := input();\nhello\nprint x;\n");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "hello\n\n");
+}